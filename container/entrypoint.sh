@@ -3,6 +3,8 @@
 set -e
 set -o pipefail
 
+# Config values can also be overridden with `VK_*` environment variables,
+# which take precedence over the file
 if [ ! -f /config/config.json ]; then
     cat <<EOF > /config/config.json
 {
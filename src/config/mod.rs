@@ -0,0 +1,874 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+
+mod validate;
+
+pub use validate::Issue;
+
+/// Interval between checks of the config file for modifications
+const WATCH_INTERVAL_MS: u64 = 2000;
+/// Stands in for secrets in printed configs
+const REDACTED: &str = "<redacted>";
+
+/// Fields which are only read while the runner is starting up. Changes to
+/// these are logged on reload, but not applied until the next restart.
+const RESTART_REQUIRED_FIELDS: &[&str] = &[
+    "address",
+    "port",
+    "storage.path",
+    "tls.enabled",
+    "tls.self_signed",
+    "host_api",
+    "events",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("Expected file, found directory: {0}")]
+    FoundDirectory(PathBuf),
+    #[error("JSON decode error: {0}")]
+    JsonDecode(serde_jsonc::Error),
+    #[error("JSON encode error: {0}")]
+    JsonEncode(serde_jsonc::Error),
+    #[error("Invalid config:{}", validate::format_issues(.0))]
+    Invalid(Vec<Issue>),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub host_api: HostApiConfig,
+    #[serde(default)]
+    pub docker: DockerConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub host_logs: HostLogsConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".to_string(),
+            port: 56088,
+            storage: StorageConfig::default(),
+            tls: TlsConfig::default(),
+            host_api: HostApiConfig::default(),
+            docker: DockerConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            host_logs: HostLogsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            templates: TemplatesConfig::default(),
+            events: EventsConfig::default(),
+            webhooks: WebhooksConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the dot-separated paths of every field which differs
+    /// between `self` and `other`
+    pub fn changed_fields(&self, other: &Config) -> Result<Vec<String>, Error> {
+        let a = serde_jsonc::to_value(self).map_err(Error::JsonEncode)?;
+        let b = serde_jsonc::to_value(other).map_err(Error::JsonEncode)?;
+
+        let mut changed = Vec::new();
+        diff_values("", &a, &b, &mut changed);
+
+        Ok(changed)
+    }
+    /// Copy with secrets replaced, for printing
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+
+        for target in &mut config.webhooks.targets {
+            if target.secret.is_some() {
+                target.secret = Some(REDACTED.to_string());
+            }
+        }
+
+        config
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StorageConfig {
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM-encoded certificate chain
+    pub cert_path: Option<PathBuf>,
+    /// PEM-encoded private key
+    pub key_path: Option<PathBuf>,
+    /// Generate a self-signed certificate and key on startup if neither
+    /// file exists yet
+    pub self_signed: bool,
+}
+
+/// Listener for the `/internal/host` API, which is served alongside the
+/// management API unless `address` or `socket_path` is set. A separate
+/// listener always uses plain HTTP.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HostApiConfig {
+    /// Address for a separate TCP listener, e.g. a Docker bridge IP. The
+    /// host API is always plain HTTP, so a separate listener is required
+    /// when TLS is enabled.
+    pub address: Option<String>,
+    pub port: u16,
+    /// Path for a separate Unix socket listener, which is mounted into
+    /// host containers
+    pub socket_path: Option<PathBuf>,
+    /// Directory the socket's directory is mounted at inside host
+    /// containers. The directory is mounted rather than the socket itself
+    /// so containers keep working when the socket is recreated.
+    pub container_socket_dir: PathBuf,
+    /// Allows `socket_path`. Host containers are given a `unix://` runner
+    /// URL, which the pinned host image isn't confirmed to support yet.
+    pub experimental_socket: bool,
+}
+
+impl Default for HostApiConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            port: 56089,
+            socket_path: None,
+            container_socket_dir: PathBuf::from("/run/volkanicmc"),
+            experimental_socket: false,
+        }
+    }
+}
+
+impl HostApiConfig {
+    /// Whether the host API has its own listener
+    pub fn is_separate(&self) -> bool {
+        self.address.is_some() || self.socket_path.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DockerConfig {
+    /// How host containers reach the runner. Ignored if the host API has
+    /// a separate TCP listener.
+    pub runner_access: RunnerAccess,
+    /// Network host containers are attached to
+    pub network: NetworkScope,
+    /// Name of the runner-managed bridge network, also used as the prefix
+    /// of per-instance networks
+    pub network_name: String,
+    /// Prevent host containers on the shared runner network from reaching
+    /// each other. Per-instance networks are always isolated.
+    pub isolate_instances: bool,
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        Self {
+            runner_access: RunnerAccess::default(),
+            network: NetworkScope::default(),
+            network_name: "volkanicmc".to_string(),
+            isolate_instances: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+pub enum NetworkScope {
+    /// Docker's default bridge network, unless `runner_access` is `bridge`
+    #[default]
+    #[serde(rename = "default")]
+    Default,
+    /// A single runner-managed network shared by every instance
+    #[serde(rename = "runner")]
+    Runner,
+    /// A runner-managed network for each instance, removed along with it
+    #[serde(rename = "instance")]
+    Instance,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+pub enum RunnerAccess {
+    /// Map `host.docker.internal` to the host gateway, which is needed on
+    /// Linux Docker engines
+    #[default]
+    #[serde(rename = "host-gateway")]
+    HostGateway,
+    /// Give host containers the gateway address of their runner-managed
+    /// network, using the shared network if `network` is `default`
+    #[serde(rename = "bridge")]
+    Bridge,
+    /// Rely on the Docker engine resolving `host.docker.internal` by
+    /// itself, as Docker Desktop does
+    #[serde(rename = "engine")]
+    Engine,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Seconds a running host may go without checking in before its
+    /// instance is flagged as unresponsive
+    pub timeout_secs: u64,
+    /// Restart the container once this many consecutive timeout windows
+    /// have been missed. Never restarted if unset.
+    pub restart_after_misses: Option<u32>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60,
+            restart_after_misses: None,
+        }
+    }
+}
+
+/// Logs shipped by hosts through the host API
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HostLogsConfig {
+    /// Number of records kept in memory per instance
+    pub buffer_size: usize,
+    /// Directory to also append each instance's records to, as JSON lines
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for HostLogsConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 1000,
+            directory: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub policy: ShutdownPolicy,
+    /// Seconds the runner may spend draining in-flight operations before
+    /// moving on regardless
+    pub deadline_secs: u64,
+    /// Seconds allowed for stopping instances under the `stop-all` policy,
+    /// counted once draining has finished
+    pub stop_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            policy: ShutdownPolicy::default(),
+            deadline_secs: 30,
+            stop_timeout_secs: 60,
+        }
+    }
+}
+
+/// What happens to running instances when the runner shuts down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+pub enum ShutdownPolicy {
+    /// Containers keep running and are picked up again on the next start
+    #[default]
+    #[serde(rename = "leave-running")]
+    LeaveRunning,
+    /// Every instance is stopped gracefully
+    #[serde(rename = "stop-all")]
+    StopAll,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TemplatesConfig {
+    /// Directory templates fetched from URLs are cached in. Only kept in
+    /// memory if unset.
+    pub cache_dir: Option<PathBuf>,
+    /// Seconds allowed for fetching a template
+    pub fetch_timeout_secs: u64,
+    /// Largest template in bytes which is fetched
+    pub max_size_bytes: u64,
+    /// Number of fetched templates kept in memory
+    pub memory_cache_size: usize,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        Self {
+            cache_dir: None,
+            fetch_timeout_secs: 30,
+            max_size_bytes: 4 * 1024 * 1024,
+            memory_cache_size: 64,
+        }
+    }
+}
+
+/// History of global events, replayed to clients which reconnect
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    /// Number of events kept for replay
+    pub history_size: usize,
+    /// File to persist the history to, as JSON lines, so it survives
+    /// restarts. Only kept in memory if unset.
+    pub history_path: Option<PathBuf>,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            history_size: 1000,
+            history_path: None,
+        }
+    }
+}
+
+/// Endpoints global events are posted to
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub targets: Vec<WebhookTarget>,
+    /// Attempts made to deliver each event before giving up
+    pub max_attempts: u32,
+    /// Seconds waited before the first retry, doubling after each attempt
+    pub backoff_secs: u64,
+    /// Seconds allowed for each attempt
+    pub timeout_secs: u64,
+    /// Number of deliveries kept in the delivery log
+    pub log_size: usize,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            max_attempts: 5,
+            backoff_secs: 2,
+            timeout_secs: 10,
+            log_size: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WebhookTarget {
+    /// Identifies the target in the delivery log
+    pub name: String,
+    pub url: String,
+    /// Key payloads are signed with, sent as
+    /// `X-Runner-Signature: sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`
+    /// with the timestamp in `X-Runner-Timestamp`
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Only events about these instances are sent, or all if unset
+    #[serde(default)]
+    pub instances: Option<HashSet<String>>,
+    /// Only these kinds of events are sent, or all but `host-log` if unset
+    #[serde(default)]
+    pub kinds: Option<HashSet<String>>,
+}
+
+/// Body posted to a webhook target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WebhookFormat {
+    /// The full event
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    /// A message summarizing the event, as accepted by Discord
+    #[serde(rename = "discord")]
+    Discord,
+    /// A message summarizing the event, as accepted by Slack
+    #[serde(rename = "slack")]
+    Slack,
+}
+
+/// Values which take precedence over the config file. Each can be set with
+/// either a flag or a `VK_*` environment variable, with flags taking
+/// precedence over environment variables.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct Overrides {
+    /// Address to bind the HTTP server to
+    #[arg(short = 'a', long, env = "VK_ADDRESS")]
+    pub address: Option<String>,
+    /// Port to bind the HTTP server to
+    #[arg(short = 'p', long, env = "VK_PORT")]
+    pub port: Option<u16>,
+    /// Path of the JSON storage file
+    #[arg(long, env = "VK_STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+    /// Serve the HTTP API over TLS
+    #[arg(long, env = "VK_TLS_ENABLED")]
+    pub tls_enabled: Option<bool>,
+    /// Path of the PEM-encoded TLS certificate chain
+    #[arg(long, env = "VK_TLS_CERT_PATH")]
+    pub tls_cert_path: Option<PathBuf>,
+    /// Path of the PEM-encoded TLS private key
+    #[arg(long, env = "VK_TLS_KEY_PATH")]
+    pub tls_key_path: Option<PathBuf>,
+    /// Generate a self-signed TLS certificate if none exists
+    #[arg(long, env = "VK_TLS_SELF_SIGNED")]
+    pub tls_self_signed: Option<bool>,
+    /// Address for a separate host API listener
+    #[arg(long, env = "VK_HOST_API_ADDRESS")]
+    pub host_api_address: Option<String>,
+    /// Port for a separate host API listener
+    #[arg(long, env = "VK_HOST_API_PORT")]
+    pub host_api_port: Option<u16>,
+    /// Unix socket path for a separate host API listener
+    #[arg(long, env = "VK_HOST_API_SOCKET_PATH")]
+    pub host_api_socket_path: Option<PathBuf>,
+    /// Directory the host API socket is mounted at inside host containers
+    #[arg(long, env = "VK_HOST_API_CONTAINER_SOCKET_DIR")]
+    pub host_api_container_socket_dir: Option<PathBuf>,
+    /// Allow the host API to be served over a Unix socket
+    #[arg(long, env = "VK_HOST_API_EXPERIMENTAL_SOCKET")]
+    pub host_api_experimental_socket: Option<bool>,
+    /// How host containers reach the runner
+    #[arg(long, env = "VK_DOCKER_RUNNER_ACCESS")]
+    pub docker_runner_access: Option<RunnerAccess>,
+    /// Network host containers are attached to
+    #[arg(long, env = "VK_DOCKER_NETWORK")]
+    pub docker_network: Option<NetworkScope>,
+    /// Name of the runner-managed Docker network
+    #[arg(long, env = "VK_DOCKER_NETWORK_NAME")]
+    pub docker_network_name: Option<String>,
+    /// Prevent host containers on the shared network from reaching each other
+    #[arg(long, env = "VK_DOCKER_ISOLATE_INSTANCES")]
+    pub docker_isolate_instances: Option<bool>,
+    /// Seconds without a host heartbeat before an instance is unresponsive
+    #[arg(long, env = "VK_HEARTBEAT_TIMEOUT_SECS")]
+    pub heartbeat_timeout_secs: Option<u64>,
+    /// Missed heartbeat windows before an unresponsive container is restarted
+    #[arg(long, env = "VK_HEARTBEAT_RESTART_AFTER_MISSES")]
+    pub heartbeat_restart_after_misses: Option<u32>,
+    /// Number of host log records kept in memory per instance
+    #[arg(long, env = "VK_HOST_LOGS_BUFFER_SIZE")]
+    pub host_logs_buffer_size: Option<usize>,
+    /// Directory to write host logs to
+    #[arg(long, env = "VK_HOST_LOGS_DIRECTORY")]
+    pub host_logs_directory: Option<PathBuf>,
+    /// What happens to running instances on shutdown
+    #[arg(long, env = "VK_SHUTDOWN_POLICY")]
+    pub shutdown_policy: Option<ShutdownPolicy>,
+    /// Seconds allowed for draining in-flight operations on shutdown
+    #[arg(long, env = "VK_SHUTDOWN_DEADLINE_SECS")]
+    pub shutdown_deadline_secs: Option<u64>,
+    /// Seconds allowed for stopping instances on shutdown
+    #[arg(long, env = "VK_SHUTDOWN_STOP_TIMEOUT_SECS")]
+    pub shutdown_stop_timeout_secs: Option<u64>,
+    /// Directory to cache templates fetched from URLs in
+    #[arg(long, env = "VK_TEMPLATES_CACHE_DIR")]
+    pub templates_cache_dir: Option<PathBuf>,
+    /// Seconds allowed for fetching a template
+    #[arg(long, env = "VK_TEMPLATES_FETCH_TIMEOUT_SECS")]
+    pub templates_fetch_timeout_secs: Option<u64>,
+    /// Largest template in bytes which is fetched
+    #[arg(long, env = "VK_TEMPLATES_MAX_SIZE_BYTES")]
+    pub templates_max_size_bytes: Option<u64>,
+    /// Number of fetched templates kept in memory
+    #[arg(long, env = "VK_TEMPLATES_MEMORY_CACHE_SIZE")]
+    pub templates_memory_cache_size: Option<usize>,
+    /// Number of global events kept for replay
+    #[arg(long, env = "VK_EVENTS_HISTORY_SIZE")]
+    pub events_history_size: Option<usize>,
+    /// File to persist the global event history to
+    #[arg(long, env = "VK_EVENTS_HISTORY_PATH")]
+    pub events_history_path: Option<PathBuf>,
+    /// Attempts made to deliver each webhook before giving up
+    #[arg(long, env = "VK_WEBHOOKS_MAX_ATTEMPTS")]
+    pub webhooks_max_attempts: Option<u32>,
+    /// Seconds waited before the first webhook retry
+    #[arg(long, env = "VK_WEBHOOKS_BACKOFF_SECS")]
+    pub webhooks_backoff_secs: Option<u64>,
+    /// Seconds allowed for each webhook attempt
+    #[arg(long, env = "VK_WEBHOOKS_TIMEOUT_SECS")]
+    pub webhooks_timeout_secs: Option<u64>,
+    /// Number of webhook deliveries kept in the delivery log
+    #[arg(long, env = "VK_WEBHOOKS_LOG_SIZE")]
+    pub webhooks_log_size: Option<usize>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(address) = &self.address {
+            config.address = address.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(storage_path) = &self.storage_path {
+            config.storage.path = Some(storage_path.clone());
+        }
+        if let Some(tls_enabled) = self.tls_enabled {
+            config.tls.enabled = tls_enabled;
+        }
+        if let Some(tls_cert_path) = &self.tls_cert_path {
+            config.tls.cert_path = Some(tls_cert_path.clone());
+        }
+        if let Some(tls_key_path) = &self.tls_key_path {
+            config.tls.key_path = Some(tls_key_path.clone());
+        }
+        if let Some(tls_self_signed) = self.tls_self_signed {
+            config.tls.self_signed = tls_self_signed;
+        }
+        if let Some(host_api_address) = &self.host_api_address {
+            config.host_api.address = Some(host_api_address.clone());
+        }
+        if let Some(host_api_port) = self.host_api_port {
+            config.host_api.port = host_api_port;
+        }
+        if let Some(host_api_socket_path) = &self.host_api_socket_path {
+            config.host_api.socket_path = Some(host_api_socket_path.clone());
+        }
+        if let Some(host_api_container_socket_dir) = &self.host_api_container_socket_dir {
+            config.host_api.container_socket_dir = host_api_container_socket_dir.clone();
+        }
+        if let Some(host_api_experimental_socket) = self.host_api_experimental_socket {
+            config.host_api.experimental_socket = host_api_experimental_socket;
+        }
+        if let Some(docker_runner_access) = self.docker_runner_access {
+            config.docker.runner_access = docker_runner_access;
+        }
+        if let Some(docker_network) = self.docker_network {
+            config.docker.network = docker_network;
+        }
+        if let Some(docker_network_name) = &self.docker_network_name {
+            config.docker.network_name = docker_network_name.clone();
+        }
+        if let Some(docker_isolate_instances) = self.docker_isolate_instances {
+            config.docker.isolate_instances = docker_isolate_instances;
+        }
+        if let Some(heartbeat_timeout_secs) = self.heartbeat_timeout_secs {
+            config.heartbeat.timeout_secs = heartbeat_timeout_secs;
+        }
+        if let Some(heartbeat_restart_after_misses) = self.heartbeat_restart_after_misses {
+            config.heartbeat.restart_after_misses = Some(heartbeat_restart_after_misses);
+        }
+        if let Some(host_logs_buffer_size) = self.host_logs_buffer_size {
+            config.host_logs.buffer_size = host_logs_buffer_size;
+        }
+        if let Some(host_logs_directory) = &self.host_logs_directory {
+            config.host_logs.directory = Some(host_logs_directory.clone());
+        }
+        if let Some(shutdown_policy) = self.shutdown_policy {
+            config.shutdown.policy = shutdown_policy;
+        }
+        if let Some(shutdown_deadline_secs) = self.shutdown_deadline_secs {
+            config.shutdown.deadline_secs = shutdown_deadline_secs;
+        }
+        if let Some(shutdown_stop_timeout_secs) = self.shutdown_stop_timeout_secs {
+            config.shutdown.stop_timeout_secs = shutdown_stop_timeout_secs;
+        }
+        if let Some(templates_cache_dir) = &self.templates_cache_dir {
+            config.templates.cache_dir = Some(templates_cache_dir.clone());
+        }
+        if let Some(templates_fetch_timeout_secs) = self.templates_fetch_timeout_secs {
+            config.templates.fetch_timeout_secs = templates_fetch_timeout_secs;
+        }
+        if let Some(templates_max_size_bytes) = self.templates_max_size_bytes {
+            config.templates.max_size_bytes = templates_max_size_bytes;
+        }
+        if let Some(templates_memory_cache_size) = self.templates_memory_cache_size {
+            config.templates.memory_cache_size = templates_memory_cache_size;
+        }
+        if let Some(events_history_size) = self.events_history_size {
+            config.events.history_size = events_history_size;
+        }
+        if let Some(events_history_path) = &self.events_history_path {
+            config.events.history_path = Some(events_history_path.clone());
+        }
+        if let Some(webhooks_max_attempts) = self.webhooks_max_attempts {
+            config.webhooks.max_attempts = webhooks_max_attempts;
+        }
+        if let Some(webhooks_backoff_secs) = self.webhooks_backoff_secs {
+            config.webhooks.backoff_secs = webhooks_backoff_secs;
+        }
+        if let Some(webhooks_timeout_secs) = self.webhooks_timeout_secs {
+            config.webhooks.timeout_secs = webhooks_timeout_secs;
+        }
+        if let Some(webhooks_log_size) = self.webhooks_log_size {
+            config.webhooks.log_size = webhooks_log_size;
+        }
+    }
+}
+
+/// Result of reloading the config file
+#[derive(Debug, Default)]
+pub struct ReloadOutcome {
+    /// Fields which were changed and are now in effect
+    pub applied: Vec<String>,
+    /// Fields which were changed, but only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+pub struct ConfigFile {
+    /// Effective config, with overrides applied
+    pub config: Config,
+    overrides: Overrides,
+    /// Keys present in the file which don't match any config field
+    unknown_fields: Vec<String>,
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    pub async fn new(path: PathBuf, overrides: Overrides) -> Result<Self, Error> {
+        if path.is_file() {
+            let (mut config, unknown_fields) = read_config(&path).await?;
+            overrides.apply(&mut config);
+
+            Ok(ConfigFile { path, config, overrides, unknown_fields })
+        } else if path.is_dir() {
+            Err(Error::FoundDirectory(path))
+        } else {
+            let mut config_file = Self {
+                path,
+                config: Config::default(),
+                overrides,
+                unknown_fields: Vec::new(),
+            };
+
+            // Only the defaults are written, overrides stay out of the file
+            config_file.update().await?;
+            config_file.overrides.apply(&mut config_file.config);
+
+            Ok(config_file)
+        }
+    }
+    pub async fn update(&self) -> Result<(), Error> {
+        let mut f = fs::File::create(&self.path).await.map_err(Error::Io)?;
+
+        let mut config_raw = serde_jsonc::to_string_pretty(&self.config).map_err(Error::JsonEncode)?;
+        config_raw.push('\n');
+
+        f.write_all(config_raw.as_bytes()).await.map_err(Error::Io)?;
+
+        Ok(())
+    }
+    /// Checks the effective config, returning every problem found
+    pub async fn validate(&self) -> Result<(), Error> {
+        let issues = validate::validate(&self.config, &self.unknown_fields, &self.path).await;
+
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(Error::Invalid(issues)),
+        }
+    }
+    /// Rereads the config file, applying every change which can be applied
+    /// while the runner is running. The current config is left untouched if
+    /// the new one can't be read or fails validation.
+    pub async fn reload(&mut self) -> Result<ReloadOutcome, Error> {
+        let (mut new_config, unknown_fields) = read_config(&self.path).await?;
+        self.overrides.apply(&mut new_config);
+
+        let issues = validate::validate(&new_config, &unknown_fields, &self.path).await;
+        if !issues.is_empty() {
+            return Err(Error::Invalid(issues));
+        }
+
+        self.unknown_fields = unknown_fields;
+
+        let mut outcome = ReloadOutcome::default();
+
+        let mut applied = serde_jsonc::to_value(&new_config).map_err(Error::JsonEncode)?;
+        let running = serde_jsonc::to_value(&self.config).map_err(Error::JsonEncode)?;
+
+        for field in self.config.changed_fields(&new_config)? {
+            if requires_restart(&field) {
+                // Keep the running value until the next restart
+                let pointer = format!("/{}", field.replace('.', "/"));
+
+                if let (Some(new), Some(old)) = (applied.pointer_mut(&pointer), running.pointer(&pointer)) {
+                    *new = old.clone();
+                }
+
+                outcome.restart_required.push(field);
+            } else {
+                outcome.applied.push(field);
+            }
+        }
+
+        self.config = serde_jsonc::from_value(applied).map_err(Error::JsonDecode)?;
+
+        Ok(outcome)
+    }
+    async fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).await.and_then(|m| m.modified()).ok()
+    }
+}
+
+/// Reloads the config whenever the file is modified, or when the runner
+/// receives SIGHUP
+pub async fn watch(config: Arc<Mutex<ConfigFile>>) -> JoinHandle<()> {
+    let mut last_modified = config.lock().await.modified().await;
+
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(o) => Some(o),
+            Err(e) => {
+                error!("Unable to listen for SIGHUP: {}", e);
+                None
+            }
+        };
+
+        loop {
+            #[cfg(unix)]
+            let hangup_recv = async {
+                match hangup.as_mut() {
+                    Some(h) => { h.recv().await; },
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup_recv = std::future::pending::<()>();
+
+            tokio::select! {
+                _ = hangup_recv => {
+                    info!("Received SIGHUP, reloading config");
+                },
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(WATCH_INTERVAL_MS)) => {
+                    let modified = config.lock().await.modified().await;
+
+                    if modified == last_modified {
+                        continue;
+                    }
+
+                    debug!("Config file modified, reloading");
+                },
+            };
+
+            let mut config_lock = config.lock().await;
+
+            last_modified = config_lock.modified().await;
+
+            match config_lock.reload().await {
+                Ok(outcome) => {
+                    for field in &outcome.applied {
+                        info!("Config field `{}` updated", field);
+                    }
+                    for field in &outcome.restart_required {
+                        warn!("Config field `{}` changed, but requires a restart to take effect", field);
+                    }
+
+                    info!("Config reloaded");
+                }
+                Err(e) => {
+                    error!("Config reload failed, keeping current config: {}", e);
+                }
+            };
+        }
+    })
+}
+
+/// Returns the config, along with the paths of any unknown keys
+async fn read_config(path: &Path) -> Result<(Config, Vec<String>), Error> {
+    let config_raw = fs::read_to_string(path).await.map_err(Error::Io)?;
+
+    let mut unknown_fields = Vec::new();
+
+    let mut de = serde_jsonc::Deserializer::from_str(&config_raw);
+    let config = serde_ignored::deserialize(&mut de, |p| unknown_fields.push(p.to_string()))
+        .map_err(Error::JsonDecode)?;
+    de.end().map_err(Error::JsonDecode)?;
+
+    Ok((config, unknown_fields))
+}
+
+fn requires_restart(field: &str) -> bool {
+    RESTART_REQUIRED_FIELDS.iter().any(|f| {
+        field == *f || field.starts_with(&format!("{}.", f))
+    })
+}
+
+fn diff_values(prefix: &str, a: &serde_jsonc::Value, b: &serde_jsonc::Value, changed: &mut Vec<String>) {
+    use serde_jsonc::Value;
+
+    match (a, b) {
+        (Value::Object(a_map), Value::Object(b_map)) => {
+            let mut keys: Vec<&String> = a_map.keys().chain(b_map.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let path = match prefix.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", prefix, key),
+                };
+
+                match (a_map.get(key), b_map.get(key)) {
+                    (Some(a_v), Some(b_v)) => diff_values(&path, a_v, b_v, changed),
+                    _ => changed.push(path),
+                }
+            }
+        }
+        _ => {
+            if a != b {
+                changed.push(prefix.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_fields() {
+        let a = Config::default();
+        let b = Config {
+            port: 1234,
+            storage: StorageConfig { path: Some(PathBuf::from("/tmp/store.json")) },
+            ..Default::default()
+        };
+
+        let changed = a.changed_fields(&b).unwrap();
+
+        assert_eq!(changed, vec!["port".to_string(), "storage.path".to_string()]);
+    }
+    #[test]
+    fn test_overrides_apply() {
+        let mut config = Config {
+            port: 1234,
+            ..Default::default()
+        };
+
+        Overrides {
+            storage_path: Some(PathBuf::from("/tmp/store.json")),
+            ..Default::default()
+        }.apply(&mut config);
+
+        assert_eq!(config.port, 1234);
+        assert_eq!(config.storage.path, Some(PathBuf::from("/tmp/store.json")));
+    }
+    #[test]
+    fn test_requires_restart() {
+        assert!(requires_restart("port"));
+        assert!(requires_restart("storage.path"));
+        assert!(!requires_restart("portal"));
+    }
+}
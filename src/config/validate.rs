@@ -0,0 +1,408 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    net::IpAddr,
+    path::Path,
+};
+use tokio::fs;
+
+use crate::global_event::EVENT_KINDS;
+
+use super::{Config, NetworkScope, RunnerAccess};
+
+/// File created next to the storage file to check whether the directory
+/// is writable
+const WRITE_PROBE_NAME: &str = ".vk-write-probe";
+
+/// A problem found while validating the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// Dot-separated path of the offending field
+    pub field: String,
+    pub message: String,
+}
+
+impl Issue {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.field, self.message)
+    }
+}
+
+pub fn format_issues(issues: &[Issue]) -> String {
+    issues.iter().map(|i| format!("\n  - {}", i)).collect()
+}
+
+/// Checks every field of the config, returning all problems found rather
+/// than stopping at the first
+pub async fn validate(config: &Config, unknown_fields: &[String], config_path: &Path) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    for field in unknown_fields {
+        issues.push(Issue::new(field, "unknown field"));
+    }
+
+    if !is_address(&config.address) {
+        issues.push(Issue::new(
+            "address",
+            format!("\"{}\" is not a valid IP address or hostname", config.address),
+        ));
+    }
+
+    if config.port == 0 {
+        issues.push(Issue::new("port", "must be between 1 and 65535"));
+    }
+
+    match &config.storage.path {
+        Some(path) => {
+            if let Some(message) = check_writable_file(path).await {
+                issues.push(Issue::new("storage.path", message));
+            }
+            if same_file(path, config_path).await {
+                issues.push(Issue::new("storage.path", "must not be the same file as the config"));
+            }
+        }
+        None => {
+            issues.push(Issue::new(
+                "storage.path",
+                "is not set (set it in the config file, with `--storage-path` or with `VK_STORAGE_PATH`)",
+            ));
+        }
+    }
+
+    if config.tls.enabled {
+        let tls = &config.tls;
+
+        for (field, path) in [("tls.cert_path", &tls.cert_path), ("tls.key_path", &tls.key_path)] {
+            match path {
+                Some(path) => {
+                    if !tls.self_signed && !path.is_file() {
+                        issues.push(Issue::new(
+                            field,
+                            format!("{} does not exist (enable `tls.self_signed` to generate it)", path.display()),
+                        ));
+                    }
+                }
+                None => issues.push(Issue::new(field, "is required when `tls.enabled` is true")),
+            };
+        }
+
+        if let (Some(cert_path), Some(key_path)) = (&tls.cert_path, &tls.key_path) {
+            if same_file(cert_path, key_path).await {
+                issues.push(Issue::new("tls.key_path", "must not be the same file as `tls.cert_path`"));
+            }
+        }
+    }
+
+    let host_api = &config.host_api;
+
+    // Hosts reach the runner by names a certificate won't cover, and have
+    // no way to trust a self-signed one
+    if config.tls.enabled && !host_api.is_separate() {
+        issues.push(Issue::new(
+            "host_api.address",
+            "is required when `tls.enabled` is true, hosts can't verify the certificate (or set `host_api.socket_path`)",
+        ));
+    }
+
+    if let Some(address) = &host_api.address {
+        if !is_address(address) {
+            issues.push(Issue::new(
+                "host_api.address",
+                format!("\"{}\" is not a valid IP address or hostname", address),
+            ));
+        }
+
+        let overlaps = address == &config.address
+            || is_unspecified(address)
+            || is_unspecified(&config.address);
+
+        if host_api.port == 0 {
+            issues.push(Issue::new("host_api.port", "must be between 1 and 65535"));
+        } else if host_api.port == config.port && overlaps {
+            issues.push(Issue::new("host_api.port", "conflicts with the management API listener on `port`"));
+        }
+        if host_api.socket_path.is_some() {
+            issues.push(Issue::new("host_api.socket_path", "conflicts with `host_api.address`, only one can be set"));
+        }
+    }
+
+    if let Some(socket_path) = &host_api.socket_path {
+        if cfg!(not(unix)) {
+            issues.push(Issue::new("host_api.socket_path", "Unix sockets are only supported on Unix platforms"));
+        }
+        if !host_api.experimental_socket {
+            issues.push(Issue::new(
+                "host_api.socket_path",
+                "requires `host_api.experimental_socket`, the host image isn't confirmed to support `unix://` runner URLs",
+            ));
+        }
+        if socket_path.is_dir() {
+            issues.push(Issue::new("host_api.socket_path", format!("{} is a directory", socket_path.display())));
+        } else if socket_path.symlink_metadata().is_ok_and(|m| !is_socket(&m)) {
+            issues.push(Issue::new(
+                "host_api.socket_path",
+                format!("{} exists and isn't a socket, so it won't be replaced", socket_path.display()),
+            ));
+        }
+        if socket_path.file_name().is_none() {
+            issues.push(Issue::new("host_api.socket_path", "must include a file name"));
+        }
+        if !host_api.container_socket_dir.is_absolute() {
+            issues.push(Issue::new("host_api.container_socket_dir", "must be an absolute path"));
+        }
+    }
+
+    if config.docker.network_name.is_empty() {
+        issues.push(Issue::new("docker.network_name", "must not be empty"));
+    }
+
+    let default_network = config.docker.network == NetworkScope::Default
+        && config.docker.runner_access != RunnerAccess::Bridge;
+
+    if config.docker.isolate_instances && default_network {
+        issues.push(Issue::new(
+            "docker.isolate_instances",
+            "has no effect on Docker's default network (set `docker.network` to `runner` or `instance`)",
+        ));
+    }
+
+    if config.heartbeat.timeout_secs == 0 {
+        issues.push(Issue::new("heartbeat.timeout_secs", "must be at least 1"));
+    }
+    if config.heartbeat.restart_after_misses == Some(0) {
+        issues.push(Issue::new("heartbeat.restart_after_misses", "must be at least 1, or unset to never restart"));
+    }
+
+    if config.host_logs.buffer_size == 0 {
+        issues.push(Issue::new("host_logs.buffer_size", "must be at least 1"));
+    }
+    if let Some(directory) = &config.host_logs.directory {
+        if directory.exists() && !directory.is_dir() {
+            issues.push(Issue::new("host_logs.directory", format!("{} is not a directory", directory.display())));
+        }
+    }
+
+    if config.shutdown.deadline_secs == 0 {
+        issues.push(Issue::new("shutdown.deadline_secs", "must be at least 1"));
+    }
+    if config.shutdown.stop_timeout_secs == 0 {
+        issues.push(Issue::new("shutdown.stop_timeout_secs", "must be at least 1"));
+    }
+
+    if let Some(cache_dir) = &config.templates.cache_dir {
+        if cache_dir.exists() && !cache_dir.is_dir() {
+            issues.push(Issue::new("templates.cache_dir", format!("{} is not a directory", cache_dir.display())));
+        }
+    }
+    if config.templates.fetch_timeout_secs == 0 {
+        issues.push(Issue::new("templates.fetch_timeout_secs", "must be at least 1"));
+    }
+    if config.templates.max_size_bytes == 0 {
+        issues.push(Issue::new("templates.max_size_bytes", "must be at least 1"));
+    }
+
+    if config.events.history_size == 0 {
+        issues.push(Issue::new("events.history_size", "must be at least 1"));
+    }
+    if let Some(history_path) = &config.events.history_path {
+        if history_path.is_dir() {
+            issues.push(Issue::new("events.history_path", format!("{} is a directory", history_path.display())));
+        }
+    }
+
+    let mut webhook_names = HashSet::new();
+
+    for (i, target) in config.webhooks.targets.iter().enumerate() {
+        let field = |name: &str| format!("webhooks.targets.{}.{}", i, name);
+
+        if target.name.trim().is_empty() {
+            issues.push(Issue::new(field("name"), "must not be empty"));
+        } else if !webhook_names.insert(target.name.as_str()) {
+            issues.push(Issue::new(field("name"), format!("\"{}\" is used by another target", target.name)));
+        }
+        match reqwest::Url::parse(&target.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => issues.push(Issue::new(field("url"), format!("\"{}\" is not an HTTP(S) URL", target.url))),
+        }
+        if target.secret.as_ref().is_some_and(|s| s.is_empty()) {
+            issues.push(Issue::new(field("secret"), "must not be empty, or unset to not sign payloads"));
+        }
+        for kind in target.kinds.iter().flatten() {
+            if !EVENT_KINDS.contains(&kind.as_str()) {
+                issues.push(Issue::new(field("kinds"), format!("\"{}\" is not an event kind", kind)));
+            }
+        }
+    }
+    if config.webhooks.max_attempts == 0 {
+        issues.push(Issue::new("webhooks.max_attempts", "must be at least 1"));
+    }
+    if config.webhooks.timeout_secs == 0 {
+        issues.push(Issue::new("webhooks.timeout_secs", "must be at least 1"));
+    }
+    if config.webhooks.log_size == 0 {
+        issues.push(Issue::new("webhooks.log_size", "must be at least 1"));
+    }
+
+    issues
+}
+
+#[cfg(unix)]
+fn is_socket(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    metadata.file_type().is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_socket(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+fn is_address(s: &str) -> bool {
+    s.parse::<IpAddr>().is_ok() || is_hostname(s)
+}
+
+fn is_unspecified(s: &str) -> bool {
+    s.parse::<IpAddr>().is_ok_and(|a| a.is_unspecified())
+}
+
+fn is_hostname(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 253 && s.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Returns a description of the problem if the file can't be written to
+async fn check_writable_file(path: &Path) -> Option<String> {
+    if path.is_dir() {
+        return Some(format!("{} is a directory, expected a file", path.display()));
+    }
+
+    if path.is_file() {
+        return match fs::OpenOptions::new().append(true).open(path).await {
+            Ok(_) => None,
+            Err(e) => Some(format!("{} is not writable: {}", path.display(), e)),
+        };
+    }
+
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    if !parent.is_dir() {
+        return Some(format!("parent directory {} does not exist", parent.display()));
+    }
+
+    let probe = parent.join(WRITE_PROBE_NAME);
+
+    match fs::File::create(&probe).await {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe).await;
+
+            None
+        }
+        Err(e) => Some(format!("directory {} is not writable: {}", parent.display(), e)),
+    }
+}
+
+async fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a).await, fs::canonicalize(b).await) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DockerConfig, HeartbeatConfig, HostApiConfig, StorageConfig, TlsConfig};
+
+    #[tokio::test]
+    async fn test_reports_every_issue() {
+        let config = Config {
+            address: "not an address".to_string(),
+            port: 0,
+            storage: StorageConfig { path: None },
+            tls: TlsConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            host_api: HostApiConfig {
+                address: Some("0.0.0.0".to_string()),
+                port: 0,
+                ..Default::default()
+            },
+            docker: DockerConfig {
+                network_name: String::new(),
+                ..Default::default()
+            },
+            heartbeat: HeartbeatConfig {
+                timeout_secs: 0,
+                ..Default::default()
+            },
+            host_logs: Default::default(),
+            shutdown: Default::default(),
+            templates: Default::default(),
+            events: Default::default(),
+            webhooks: Default::default(),
+        };
+
+        let issues = validate(&config, &["storage.pth".to_string()], Path::new("config.json")).await;
+
+        let fields: Vec<&str> = issues.iter().map(|i| i.field.as_str()).collect();
+
+        assert_eq!(fields, vec![
+            "storage.pth",
+            "address",
+            "port",
+            "storage.path",
+            "tls.cert_path",
+            "tls.key_path",
+            "host_api.port",
+            "docker.network_name",
+            "heartbeat.timeout_secs",
+        ]);
+    }
+    #[tokio::test]
+    async fn test_tls_requires_separate_host_api() {
+        let mut config = Config {
+            storage: StorageConfig { path: Some("store.json".into()) },
+            tls: TlsConfig {
+                enabled: true,
+                self_signed: true,
+                cert_path: Some("cert.pem".into()),
+                key_path: Some("key.pem".into()),
+            },
+            ..Default::default()
+        };
+
+        let fields = |issues: Vec<Issue>| issues.into_iter().map(|i| i.field).collect::<Vec<_>>();
+
+        assert_eq!(fields(validate(&config, &[], Path::new("config.json")).await), vec!["host_api.address"]);
+
+        config.host_api.address = Some("172.17.0.1".to_string());
+
+        assert!(validate(&config, &[], Path::new("config.json")).await.is_empty());
+    }
+    #[test]
+    fn test_is_hostname() {
+        assert!(is_hostname("localhost"));
+        assert!(is_hostname("host.docker.internal"));
+        assert!(!is_hostname("-bad.example"));
+        assert!(!is_hostname("bad..example"));
+    }
+}
@@ -1,29 +1,370 @@
 use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast;
-use tracing::debug;
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::broadcast,
+};
+use tracing::{debug, error, warn};
 
-use crate::instance::PubInstance;
+use crate::{
+    config::EventsConfig,
+    instance::{LogRecord, PubInstance},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("JSON encode error: {0}")]
+    JsonEncode(serde_jsonc::Error),
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum GlobalEvent {
     #[serde(rename = "modify-instance")]
-    ModifyInstance { id: String, instance: PubInstance },
+    ModifyInstance { id: String, instance: Box<PubInstance> },
     #[serde(rename = "delete-instance")]
     DeleteInstance { id: String },
+    /// The instance's host hasn't checked in within the heartbeat timeout
+    #[serde(rename = "host-unresponsive")]
+    HostUnresponsive { id: String, last_heartbeat_age: Option<u64>, missed: u32 },
+    #[serde(rename = "host-log")]
+    HostLog { id: String, records: Vec<LogRecord> },
+    /// Only sent to a single subscriber, when events it missed can't be
+    /// replayed, so it should fetch the current state again
+    #[serde(rename = "resync-required")]
+    ResyncRequired {},
+}
+
+/// Kinds of [`GlobalEvent`], as named when serialized
+pub const EVENT_KINDS: &[&str] = &[
+    "modify-instance",
+    "delete-instance",
+    "host-unresponsive",
+    "host-log",
+    "resync-required",
+];
+
+impl GlobalEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ModifyInstance { .. } => "modify-instance",
+            Self::DeleteInstance { .. } => "delete-instance",
+            Self::HostUnresponsive { .. } => "host-unresponsive",
+            Self::HostLog { .. } => "host-log",
+            Self::ResyncRequired {} => "resync-required",
+        }
+    }
+    /// ID of the instance the event is about
+    pub fn instance_id(&self) -> Option<&str> {
+        match self {
+            Self::ModifyInstance { id, .. }
+            | Self::DeleteInstance { id }
+            | Self::HostUnresponsive { id, .. }
+            | Self::HostLog { id, .. } => Some(id),
+            Self::ResyncRequired {} => None,
+        }
+    }
+}
+
+/// Narrows which events a subscriber receives, with unset fields matching
+/// everything
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct EventFilter {
+    pub instances: Option<HashSet<String>>,
+    pub kinds: Option<HashSet<String>>,
+}
+
+impl EventFilter {
+    /// Returns the kinds which don't exist
+    pub fn unknown_kinds(&self) -> Vec<String> {
+        let mut unknown: Vec<String> = self.kinds.iter()
+            .flatten()
+            .filter(|k| !EVENT_KINDS.contains(&k.as_str()))
+            .cloned()
+            .collect();
+
+        unknown.sort();
+
+        unknown
+    }
+    /// Resync events always match, since subscribers can't ignore them
+    pub fn matches(&self, event: &GlobalEvent) -> bool {
+        if let GlobalEvent::ResyncRequired {} = event {
+            return true;
+        }
+
+        let instance_match = match (&self.instances, event.instance_id()) {
+            (Some(instances), Some(id)) => instances.contains(id),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        let kind_match = match &self.kinds {
+            Some(kinds) => kinds.contains(event.kind()),
+            None => true,
+        };
+
+        instance_match && kind_match
+    }
+}
+
+/// An event along with its position in the history
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StampedEvent {
+    pub id: u64,
+    pub event: GlobalEvent,
+}
+
+/// Events missed by a subscriber since the last one it received
+pub enum Replay {
+    Events(Vec<StampedEvent>),
+    /// Some of the missed events are no longer in the history. `latest_id`
+    /// is where the subscriber continues from after resyncing.
+    ResyncRequired { latest_id: u64 },
+}
+
+struct History {
+    next_id: u64,
+    events: VecDeque<StampedEvent>,
+    capacity: usize,
+}
+
+/// Broadcasts global events, keeping a bounded history of them so
+/// subscribers can catch up on events missed while disconnected
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<StampedEvent>,
+    history: Arc<Mutex<History>>,
+}
+
+impl EventBus {
+    pub async fn new(config: &EventsConfig) -> Result<Self, Error> {
+        let capacity = config.history_size;
+
+        let events = match &config.history_path {
+            Some(path) => load_history(path, capacity).await?,
+            None => VecDeque::new(),
+        };
+
+        // Without a persisted history, IDs start from the current time so
+        // they keep increasing across restarts
+        let next_id = match events.back() {
+            Some(o) => o.id + 1,
+            None => chrono::Utc::now().timestamp_millis().max(1) as u64,
+        };
+
+        let (tx, rx) = broadcast::channel(4096);
+
+        let bus = Self {
+            tx,
+            history: Arc::new(Mutex::new(History { next_id, events, capacity })),
+        };
+
+        if let Some(path) = &config.history_path {
+            let snapshot = bus.history.lock().unwrap().events.clone();
+            write_history(path, &snapshot).await?;
+
+            tokio::spawn(persist_history(path.clone(), bus.history.clone(), bus.subscribe()));
+        }
+
+        tokio::spawn(async move {
+            let mut rx = rx;
+
+            loop {
+                match rx.recv().await {
+                    Ok(event) => debug!("Global event issued: {:#?}", event),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("Event logger lagged behind, skipping {} events", skipped);
+                    }
+                    // Every sender is dropped on shutdown
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(bus)
+    }
+    pub fn send(&self, event: GlobalEvent) -> Result<usize, broadcast::error::SendError<StampedEvent>> {
+        let mut history = self.history.lock().unwrap();
+
+        let event = StampedEvent { id: history.next_id, event };
+        history.next_id += 1;
+
+        history.events.push_back(event.clone());
+        while history.events.len() > history.capacity {
+            history.events.pop_front();
+        }
+
+        // Sent while holding the lock, so `subscribe_from` can't miss or
+        // duplicate an event between the replay and the receiver
+        self.tx.send(event)
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<StampedEvent> {
+        self.tx.subscribe()
+    }
+    /// Subscribes, along with the events issued after `last_id`
+    pub fn subscribe_from(&self, last_id: u64) -> (Replay, broadcast::Receiver<StampedEvent>) {
+        let history = self.history.lock().unwrap();
+        let rx = self.tx.subscribe();
+
+        let latest_id = history.next_id - 1;
+
+        let oldest_id = match history.events.front() {
+            Some(o) => o.id,
+            None => history.next_id,
+        };
+
+        // IDs ahead of the history are from before a restart which lost it
+        if last_id > latest_id || last_id + 1 < oldest_id {
+            return (Replay::ResyncRequired { latest_id }, rx);
+        }
+
+        let events = history.events.iter()
+            .filter(|e| e.id > last_id)
+            .cloned()
+            .collect();
+
+        (Replay::Events(events), rx)
+    }
+}
+
+async fn load_history(path: &Path, capacity: usize) -> Result<VecDeque<StampedEvent>, Error> {
+    let mut events = VecDeque::new();
+
+    if !path.is_file() {
+        return Ok(events);
+    }
+
+    let raw = fs::read_to_string(path).await.map_err(Error::Io)?;
+
+    for line in raw.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_jsonc::from_str::<StampedEvent>(line) {
+            Ok(o) => events.push_back(o),
+            Err(e) => warn!("Skipping invalid event history entry: {}", e),
+        }
+
+        if events.len() > capacity {
+            events.pop_front();
+        }
+    }
+
+    Ok(events)
+}
+
+/// Replaces the history file with `events`
+async fn write_history(path: &Path, events: &VecDeque<StampedEvent>) -> Result<(), Error> {
+    let mut raw = String::new();
+
+    for event in events {
+        raw.push_str(&serde_jsonc::to_string(event).map_err(Error::JsonEncode)?);
+        raw.push('\n');
+    }
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, raw).await.map_err(Error::Io)?;
+    fs::rename(&tmp_path, path).await.map_err(Error::Io)?;
+
+    Ok(())
+}
+
+async fn append_history(path: &Path, event: &StampedEvent) -> Result<(), Error> {
+    let mut line = serde_jsonc::to_string(event).map_err(Error::JsonEncode)?;
+    line.push('\n');
+
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(Error::Io)?;
+
+    f.write_all(line.as_bytes()).await.map_err(Error::Io)?;
+    f.flush().await.map_err(Error::Io)?;
+
+    Ok(())
+}
+
+/// Appends each event to the history file, rewriting it from memory once
+/// it holds twice as many entries as are kept. Only holds the history, not
+/// the sender, so it ends once the bus is dropped.
+async fn persist_history(path: PathBuf, history: Arc<Mutex<History>>, mut rx: broadcast::Receiver<StampedEvent>) {
+    let snapshot = || history.lock().unwrap().events.clone();
+
+    let capacity = history.lock().unwrap().capacity;
+    let mut lines = snapshot().len();
+
+    loop {
+        let result = match rx.recv().await {
+            Ok(event) => {
+                lines += 1;
+
+                match lines > capacity * 2 {
+                    true => {
+                        let events = snapshot();
+                        lines = events.len();
+
+                        write_history(&path, &events).await
+                    }
+                    false => append_history(&path, &event).await,
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                let events = snapshot();
+                lines = events.len();
+
+                write_history(&path, &events).await
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if let Err(e) = result {
+            error!("Error persisting event history: {}", e);
+        }
+    }
 }
 
-pub fn init_channel() -> broadcast::Sender<GlobalEvent> {
-    let (tx, rx) = broadcast::channel(4096);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    tokio::spawn(async move {
-        let mut rx = rx;
+    #[tokio::test]
+    async fn test_subscribe_from() {
+        let bus = EventBus::new(&EventsConfig { history_size: 2, history_path: None }).await.unwrap();
 
-        loop {
-            let event = rx.recv().await.unwrap();
+        for id in ["a", "b", "c"] {
+            let _ = bus.send(GlobalEvent::DeleteInstance { id: id.to_string() });
+        }
 
-            debug!("Global event issued: {:#?}", event);
+        let latest_id = bus.history.lock().unwrap().next_id - 1;
+
+        match bus.subscribe_from(latest_id - 1).0 {
+            Replay::Events(events) => assert_eq!(events.iter().map(|e| e.id).collect::<Vec<_>>(), vec![latest_id]),
+            Replay::ResyncRequired { .. } => panic!("expected replay"),
         }
-    });
 
-    tx
+        assert!(matches!(bus.subscribe_from(latest_id).0, Replay::Events(e) if e.is_empty()));
+        assert!(matches!(bus.subscribe_from(latest_id - 3).0, Replay::ResyncRequired { .. }));
+        assert!(matches!(bus.subscribe_from(latest_id + 1).0, Replay::ResyncRequired { .. }));
+    }
+    #[test]
+    fn test_filter() {
+        let filter = EventFilter {
+            instances: Some(HashSet::from(["a".to_string()])),
+            kinds: Some(HashSet::from(["delete-instance".to_string(), "unknown".to_string()])),
+        };
+
+        assert!(filter.matches(&GlobalEvent::DeleteInstance { id: "a".to_string() }));
+        assert!(!filter.matches(&GlobalEvent::DeleteInstance { id: "b".to_string() }));
+        assert!(!filter.matches(&GlobalEvent::HostLog { id: "a".to_string(), records: Vec::new() }));
+        assert!(filter.matches(&GlobalEvent::ResyncRequired {}));
+        assert_eq!(filter.unknown_kinds(), vec!["unknown"]);
+    }
 }
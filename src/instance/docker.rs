@@ -1,33 +1,54 @@
 use bollard::{
     container::{self, CreateContainerOptions, ListContainersOptions, RemoveContainerOptions, StartContainerOptions},
+    secret::HostConfig,
     Docker
 };
-use futures_util::future::join_all;
+use futures_util::{future::join_all, StreamExt};
 use rand::Rng;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{sync::{broadcast, Mutex}, task::JoinHandle};
-use tracing::{debug, info, error};
+use std::{collections::{HashMap, VecDeque}, net::IpAddr, sync::Arc};
+use tokio::{io::AsyncWriteExt, sync::Mutex, task::JoinHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, error, warn};
 
 use crate::{
-    config::ConfigFile,
-    global_event::GlobalEvent,
+    config::{Config, ConfigFile, NetworkScope, RunnerAccess, ShutdownConfig, ShutdownPolicy},
+    global_event::{EventBus, GlobalEvent},
     storage::JsonStorageProvider,
+    template::{self, TemplateStore},
 };
 
 use super::{
+    env,
+    log,
+    network,
+    restart,
+    ContainerDetail,
+    EnvVars,
     Error,
+    HostPhase,
+    HostProgress,
     Instance,
+    InstanceDetail,
     InstanceList,
+    InstanceModification,
     InstanceRequest,
     InstanceStatus,
+    InstanceType,
+    LogRecord,
+    PortMapping,
     PubInstance,
     PubInstanceList,
-    StoredInstance
+    StopReason,
+    StopSettings,
+    StoredInstance,
+    Variables,
+    volkanic::SourceCache,
+    VolkanicSource,
 };
 
 const HOST_IMAGE: &str = "ghcr.io/8bitz0/volkanicmc-host:0.2.0";
 const MAX_TOKEN_GEN_ITER: usize = 128;
-const RUNNER_ADDR_PREFIX: &str = "http://host.docker.internal:";
+const RUNNER_HOST: &str = "host.docker.internal";
 const INSTANCE_CHECK_INTERVAL_MS: u64 = 750;
 const INSTANCE_CHECK_CONTAINER_ID_LOCK_TIMEOUT_MS: u64 = 50;
 
@@ -35,20 +56,31 @@ const INSTANCE_CHECK_CONTAINER_ID_LOCK_TIMEOUT_MS: u64 = 50;
 pub struct DockerInstanceProvider {
     config: Arc<Mutex<ConfigFile>>,
     instances: Arc<Mutex<InstanceList>>,
-    g_event_tx: broadcast::Sender<GlobalEvent>,
+    g_event_tx: EventBus,
     storage: Arc<Mutex<JsonStorageProvider>>,
     docker_handle: Arc<Docker>,
     bg_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Cancelled once the runner starts shutting down
+    shutdown: CancellationToken,
+    /// Operations started in the background, drained on shutdown. Shared
+    /// with the webhook dispatcher so pending deliveries are drained too.
+    tasks: TaskTracker,
+    sources: SourceCache,
+    templates: Arc<TemplateStore>,
 }
 
 impl DockerInstanceProvider {
     pub async fn new(
         config: Arc<Mutex<ConfigFile>>,
-        g_event_tx: broadcast::Sender<GlobalEvent>,
+        g_event_tx: EventBus,
         storage: Arc<Mutex<JsonStorageProvider>>,
+        shutdown: CancellationToken,
+        tasks: TaskTracker,
     ) -> Result<Self, Error> {
         let docker_handle = Docker::connect_with_local_defaults().map_err(Error::Docker)?;
 
+        let templates = TemplateStore::new(&config.lock().await.config).map_err(Error::Template)?;
+
         info!("Connected to Docker");
 
         info!("Loading instances from storage");
@@ -60,6 +92,10 @@ impl DockerInstanceProvider {
             storage: storage.clone(),
             docker_handle: Arc::new(docker_handle),
             bg_handle: Arc::new(Mutex::new(None)),
+            shutdown,
+            tasks,
+            sources: SourceCache::new(),
+            templates: Arc::new(templates),
         };
 
         let total_to_load: usize = storage.lock().await.list_instances().await.map_err(Error::Storage)?.len();
@@ -74,6 +110,25 @@ impl DockerInstanceProvider {
                 status: Arc::new(Mutex::new(InstanceStatus::Inactive)),
                 host_com_token: Arc::new(Mutex::new(inst.host_com_token.clone())),
                 last_con: Arc::new(Mutex::new(None)),
+                running_since: Arc::new(Mutex::new(None)),
+                progress: Arc::new(Mutex::new(None)),
+                logs: Arc::new(Mutex::new(VecDeque::new())),
+                restart_policy: Arc::new(Mutex::new(inst.restart_policy.clone())),
+                crash_count: Arc::new(Mutex::new(0)),
+                last_exit_code: Arc::new(Mutex::new(None)),
+                restart_at: Arc::new(Mutex::new(None)),
+                stop: Arc::new(Mutex::new(inst.stop.clone())),
+                last_stop_reason: Arc::new(Mutex::new(None)),
+                needs_recreate: Arc::new(Mutex::new(inst.needs_recreate)),
+                variables: Arc::new(Mutex::new(inst.variables.clone())),
+                env: Arc::new(Mutex::new(inst.env.clone())),
+                // Containers created before environment variables were
+                // added have none set
+                container_env_digest: Arc::new(Mutex::new(match (&inst.container_id, &inst.container_env_digest) {
+                    (Some(_), None) => Some(env::digest(&EnvVars::new())),
+                    (Some(_), digest) => digest.clone(),
+                    (None, _) => None,
+                })),
                 container_id: Arc::new(Mutex::new(inst.container_id.clone())),
             };
 
@@ -103,7 +158,10 @@ impl DockerInstanceProvider {
         Ok(list)
     }
     /// Returns the ID of the new instance
-    pub async fn new_instance(&self, inst: InstanceRequest) -> Result<String, Error> {
+    pub async fn new_instance(&self, mut inst: InstanceRequest) -> Result<String, Error> {
+        env::validate(&inst.env)?;
+        self.pin_template(&mut inst.inst_type).await?;
+
         let id = super::unique_id(self.instances.lock().await.keys().cloned().collect()).await?;
 
         let tokens: Vec<_> = join_all(
@@ -118,20 +176,29 @@ impl DockerInstanceProvider {
             status: Arc::new(Mutex::new(InstanceStatus::Inactive)),
             host_com_token: Arc::new(Mutex::new(token.clone())),
             last_con: Arc::new(Mutex::new(None)),
+            running_since: Arc::new(Mutex::new(None)),
+            progress: Arc::new(Mutex::new(None)),
+            logs: Arc::new(Mutex::new(VecDeque::new())),
+            restart_policy: Arc::new(Mutex::new(inst.restart_policy)),
+            crash_count: Arc::new(Mutex::new(0)),
+            last_exit_code: Arc::new(Mutex::new(None)),
+            restart_at: Arc::new(Mutex::new(None)),
+            stop: Arc::new(Mutex::new(inst.stop)),
+            last_stop_reason: Arc::new(Mutex::new(None)),
+            needs_recreate: Arc::new(Mutex::new(false)),
+            variables: Arc::new(Mutex::new(inst.variables)),
+            env: Arc::new(Mutex::new(inst.env)),
+            container_env_digest: Arc::new(Mutex::new(None)),
             container_id: Arc::new(Mutex::new(None)),
         };
 
         self.instances.lock().await.insert(id.clone(), new_instance.clone());
 
-        self.storage.lock().await.update_instance(id.clone(), StoredInstance {
-            name: inst.name,
-            inst_type: inst.inst_type,
-            host_com_token: token.clone(),
-            container_id: None,
-        }).await.map_err(Error::Storage)?;
+        self.storage.lock().await.update_instance(id.clone(), to_stored_instance(&new_instance, None).await)
+            .await.map_err(Error::Storage)?;
 
         let _ = self.g_event_tx
-            .send(GlobalEvent::ModifyInstance { id: id.clone(), instance: to_pub_instance(&new_instance).await });
+            .send(GlobalEvent::ModifyInstance { id: id.clone(), instance: Box::new(to_pub_instance(&new_instance).await) });
 
         Ok(id)
     }
@@ -141,9 +208,13 @@ impl DockerInstanceProvider {
 
         let provider = self.clone();
 
+        if !self.accepting_operations("delete", &id) {
+            return Ok(());
+        }
+
         self.set_inst_status(&id, InstanceStatus::Deleting).await?;
 
-        tokio::spawn(async move {
+        self.tasks.spawn(async move {
             {
                 // Lock instances
                 let mut instances = provider.instances.lock().await.clone();
@@ -165,6 +236,18 @@ impl DockerInstanceProvider {
                     };
                 } 
             }
+
+            // Per-instance networks are removed even if the network settings
+            // have since been changed
+            if let Err(e) = network::remove_instance_networks(&provider.docker_handle, &id).await {
+                error!("Error removing networks of instance {}: {}", id, e);
+            }
+
+            if let Some(directory) = provider.config.lock().await.config.host_logs.directory.clone() {
+                if let Err(e) = log::remove_file(&directory, &id).await {
+                    error!("Error removing host log file of instance {}: {}", id, e);
+                }
+            }
     
             provider.instances.lock().await.remove(&id.to_string());
     
@@ -186,6 +269,89 @@ impl DockerInstanceProvider {
 
         Ok(())
     }
+    /// Applies the changes and persists them, flagging the container for
+    /// recreation if anything it was created with has changed
+    pub async fn modify_instance(&self, id: &str, mut modification: InstanceModification) -> Result<PubInstance, Error> {
+        let inst = self.instances.lock().await.get(id)
+            .ok_or(Error::InstanceNotFound(id.to_string()))?
+            .clone();
+
+        if *inst.status.lock().await == InstanceStatus::Deleting {
+            return Err(Error::InstanceBusy(id.to_string()));
+        }
+
+        // Everything is checked before anything is changed
+        if modification.name.as_ref().is_some_and(|n| n.trim().is_empty()) {
+            return Err(Error::InvalidName);
+        }
+        let env = match &modification.env {
+            Some(changes) => {
+                let mut env = inst.env.lock().await.clone();
+
+                for (name, var) in changes {
+                    match var {
+                        Some(var) => {
+                            env::validate_name(name)?;
+                            env.insert(name.clone(), var.clone());
+                        }
+                        None => {
+                            env.remove(name);
+                        }
+                    };
+                }
+
+                Some(env)
+            }
+            None => None,
+        };
+
+        if modification.inst_type.is_some() || modification.variables.is_some() {
+            let mut inst_type = match &modification.inst_type {
+                Some(o) => o.clone(),
+                None => inst.inst_type.lock().await.clone(),
+            };
+            let variables = match &modification.variables {
+                Some(o) => o.clone(),
+                None => inst.variables.lock().await.clone(),
+            };
+
+            self.check_instance(&mut inst_type, &variables).await?;
+
+            // Keeps the pinned template version
+            if modification.inst_type.is_some() {
+                modification.inst_type = Some(inst_type);
+            }
+        }
+
+        if let Some(name) = modification.name {
+            *inst.name.lock().await = name;
+        }
+
+        apply_definition(&inst, modification.inst_type, modification.variables).await;
+
+        if let Some(restart_policy) = modification.restart_policy {
+            *inst.restart_policy.lock().await = restart_policy;
+        }
+
+        if let Some(stop) = modification.stop {
+            *inst.stop.lock().await = stop;
+        }
+
+        if let Some(env) = env {
+            *inst.env.lock().await = env;
+        }
+
+        let container_id = inst.container_id.lock().await.clone();
+
+        self.storage.lock().await.update_instance(id.to_string(), to_stored_instance(&inst, container_id).await)
+            .await.map_err(Error::Storage)?;
+
+        let pub_instance = to_pub_instance(&inst).await;
+
+        let _ = self.g_event_tx.send(GlobalEvent::ModifyInstance { id: id.to_string(), instance: Box::new(pub_instance.clone()) });
+
+        Ok(pub_instance)
+    }
     pub async fn get_instance(&self, id: impl std::fmt::Display) -> Option<PubInstance> {
         if let Some(inst) = self.instances.lock().await.get(&id.to_string()) {
             Some(to_pub_instance(inst).await)
@@ -193,12 +359,165 @@ impl DockerInstanceProvider {
             None
         }
     }
+    /// Returns the raw template the instance is built from, fetching it
+    /// if necessary
+    pub async fn resolve_source(&self, source: &VolkanicSource) -> Result<Vec<u8>, Error> {
+        let templates_config = self.config.lock().await.config.templates.clone();
+
+        self.sources.resolve(source, &templates_config, &self.templates).await
+    }
+    pub fn templates(&self) -> Arc<TemplateStore> {
+        self.templates.clone()
+    }
+    /// Whether any instance is built from the library template
+    pub async fn template_in_use(&self, name: &str) -> bool {
+        for inst in self.instances.lock().await.values() {
+            let InstanceType::Volkanic { source } = &*inst.inst_type.lock().await;
+
+            if matches!(source, VolkanicSource::Template { name: n, .. } if n == name) {
+                return true;
+            }
+        }
+
+        false
+    }
+    /// Pins library templates without a version to the latest one, making
+    /// sure the referenced version exists
+    async fn pin_template(&self, inst_type: &mut InstanceType) -> Result<(), Error> {
+        let InstanceType::Volkanic { source } = inst_type;
+
+        if let VolkanicSource::Template { name, version } = source {
+            let (pinned, _) = self.templates.get(name, *version).await.map_err(Error::Template)?;
+
+            *version = Some(pinned);
+        }
+
+        Ok(())
+    }
+    pub fn check_env(&self, env: &EnvVars) -> Result<(), Error> {
+        env::validate(env)
+    }
+    /// Pins the template, then fetches and checks it along with the
+    /// variable values, so problems are reported before the instance is
+    /// created or changed
+    pub async fn check_instance(&self, inst_type: &mut InstanceType, variables: &Variables) -> Result<(), Error> {
+        self.pin_template(inst_type).await?;
+
+        let InstanceType::Volkanic { source } = inst_type;
+
+        let data = self.resolve_source(source).await?;
+
+        let mut issues = template::check(&data);
+
+        if issues.is_empty() {
+            issues = template::check_values(&template::variables(&data), variables);
+        }
+
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(Error::InvalidTemplate(issues)),
+        }
+    }
+    /// Moves an instance built from a library template to the template's
+    /// latest version
+    pub async fn upgrade_template(&self, id: &str) -> Result<PubInstance, Error> {
+        let inst_type = self.instances.lock().await.get(id)
+            .ok_or(Error::InstanceNotFound(id.to_string()))?
+            .inst_type.lock().await.clone();
+
+        let InstanceType::Volkanic { source } = inst_type;
+
+        let name = match source {
+            VolkanicSource::Template { name, .. } => name,
+            _ => return Err(Error::InvalidSource("instance isn't built from a library template".to_string())),
+        };
+
+        let latest = self.templates.info(&name).await.map_err(Error::Template)?.latest;
+
+        info!("Upgrading instance {} to version {} of template {}", id, latest, name);
+
+        self.modify_instance(id, InstanceModification {
+            inst_type: Some(InstanceType::Volkanic {
+                source: VolkanicSource::Template { name, version: Some(latest) },
+            }),
+            ..Default::default()
+        }).await
+    }
+    pub async fn get_instance_detail(&self, id: &str) -> Option<InstanceDetail> {
+        let inst = self.instances.lock().await.get(id)?.clone();
+
+        let container_id = inst.container_id.lock().await.clone();
+
+        let container = match container_id {
+            Some(container_id) => Some(self.container_detail(container_id).await),
+            None => None,
+        };
+
+        let last_heartbeat = inst.last_con.lock().await.map(|t| t.and_utc());
+
+        Some(InstanceDetail {
+            instance: to_pub_instance(&inst).await,
+            container,
+            last_heartbeat,
+        })
+    }
+    /// Falls back to only the ID if the container can't be inspected
+    async fn container_detail(&self, container_id: String) -> ContainerDetail {
+        let c = match self.docker_handle.inspect_container(&container_id, None).await {
+            Ok(o) => o,
+            Err(e) => {
+                debug!("Unable to inspect container {}: {}", container_id, e);
+
+                return ContainerDetail {
+                    id: container_id,
+                    image: None,
+                    created: None,
+                    ports: Vec::new(),
+                };
+            }
+        };
+
+        let mut ports: Vec<PortMapping> = c.network_settings
+            .and_then(|n| n.ports)
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|(container_port, bindings)| match bindings {
+                Some(bindings) if !bindings.is_empty() => bindings.into_iter()
+                    .map(|b| PortMapping {
+                        container_port: container_port.clone(),
+                        host_ip: b.host_ip,
+                        host_port: b.host_port.and_then(|p| p.parse().ok()),
+                    })
+                    .collect(),
+                _ => vec![PortMapping {
+                    container_port,
+                    host_ip: None,
+                    host_port: None,
+                }],
+            })
+            .collect();
+
+        ports.sort_by(|a, b| a.container_port.cmp(&b.container_port));
+
+        ContainerDetail {
+            id: container_id,
+            image: c.config.and_then(|c| c.image),
+            created: c.created,
+            ports,
+        }
+    }
     pub async fn start_instance<I: std::fmt::Display>(&self, id: I) {
         let id = id.to_string();
 
+        if !self.accepting_operations("start", &id) {
+            return;
+        }
+
+        self.reset_restart_state(&id).await;
+
         let provider = self.clone();
 
-        tokio::spawn(async move {
+        self.tasks.spawn(async move {
             match provider.start_host(id.to_string()).await {
                 Ok(_) => {}
                 Err(e) => {
@@ -210,10 +529,16 @@ impl DockerInstanceProvider {
     pub async fn stop_instance<I: std::fmt::Display>(&self, id: I) {
         let id = id.to_string();
 
+        if !self.accepting_operations("stop", &id) {
+            return;
+        }
+
+        self.reset_restart_state(&id).await;
+
         let provider = self.clone();
 
-        tokio::spawn(async move {
-            match provider.stop_host(id.to_string()).await {
+        self.tasks.spawn(async move {
+            match provider.stop_host(id.to_string(), StopReason::Requested).await {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Error stopping instance: {}", e);
@@ -221,6 +546,121 @@ impl DockerInstanceProvider {
             };
         });
     }
+    pub async fn kill_instance<I: std::fmt::Display>(&self, id: I) {
+        let id = id.to_string();
+
+        if !self.accepting_operations("kill", &id) {
+            return;
+        }
+
+        self.reset_restart_state(&id).await;
+
+        let provider = self.clone();
+
+        self.tasks.spawn(async move {
+            if let Err(e) = provider.kill_host(&id).await {
+                error!("Error killing instance: {}", e);
+            }
+        });
+    }
+    pub async fn restart_instance<I: std::fmt::Display>(&self, id: I) {
+        let id = id.to_string();
+
+        if !self.accepting_operations("restart", &id) {
+            return;
+        }
+
+        self.reset_restart_state(&id).await;
+
+        let provider = self.clone();
+
+        self.tasks.spawn(async move {
+            let inactive = match provider.instances.lock().await.get(&id) {
+                Some(inst) => *inst.status.lock().await == InstanceStatus::Inactive,
+                None => true,
+            };
+
+            if !inactive {
+                if let Err(e) = provider.stop_host(&id, StopReason::Restart).await {
+                    error!("Error stopping instance for restart: {}", e);
+
+                    return;
+                }
+            }
+
+            if let Err(e) = provider.start_host(&id).await {
+                error!("Error starting instance for restart: {}", e);
+            }
+        });
+    }
+    /// Returns `false`, logging why, once the runner is shutting down
+    fn accepting_operations(&self, action: &str, id: &str) -> bool {
+        if self.shutdown.is_cancelled() {
+            warn!("Not performing {} on instance {}, the runner is shutting down", action, id);
+
+            return false;
+        }
+
+        true
+    }
+    /// Waits for in-flight operations to finish, applies the shutdown
+    /// policy, then flushes storage. Nothing past the deadline is waited on.
+    pub async fn shutdown(&self, config: &ShutdownConfig) {
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(config.deadline_secs);
+
+        self.shutdown.cancel();
+        self.tasks.close();
+
+        if let Some(handle) = self.bg_handle.lock().await.take() {
+            let _ = tokio::time::timeout_at(deadline, handle).await;
+        }
+
+        if !self.tasks.is_empty() {
+            info!("Waiting for {} in-flight operations", self.tasks.len());
+        }
+
+        if tokio::time::timeout_at(deadline, self.tasks.wait()).await.is_err() {
+            warn!("Shutdown deadline reached with operations still in progress");
+        }
+
+        if config.policy == ShutdownPolicy::StopAll {
+            let mut ids = Vec::new();
+
+            for (id, inst) in self.instances.lock().await.iter() {
+                if *inst.status.lock().await != InstanceStatus::Inactive {
+                    ids.push(id.clone());
+                }
+            }
+
+            info!("Stopping {} running instances", ids.len());
+
+            // Has its own budget, so slow draining can't leave instances
+            // running
+            let stop_timeout = tokio::time::Duration::from_secs(config.stop_timeout_secs);
+            let stops = join_all(ids.iter().map(|id| self.stop_host(id, StopReason::Shutdown)));
+
+            match tokio::time::timeout(stop_timeout, stops).await {
+                Ok(results) => {
+                    for e in results.into_iter().filter_map(Result::err) {
+                        error!("Error stopping instance during shutdown: {}", e);
+                    }
+                }
+                Err(_) => warn!("Shutdown deadline reached before every instance stopped"),
+            };
+        }
+
+        if let Err(e) = self.storage.lock().await.flush().await {
+            error!("Error flushing storage: {}", e);
+        }
+    }
+    /// Clears crash tracking and any pending automatic restart, as the
+    /// instance is being managed by hand
+    async fn reset_restart_state(&self, id: &str) {
+        if let Some(inst) = self.instances.lock().await.get(id) {
+            *inst.crash_count.lock().await = 0;
+            *inst.restart_at.lock().await = None;
+        }
+    }
     pub async fn find_token(&self, token: &str) -> Option<String> {
         for i in self.instances.lock().await.iter() {
             if i.1.host_com_token.lock().await.clone() == token {
@@ -231,13 +671,76 @@ impl DockerInstanceProvider {
         None
     }
     pub async fn set_last_con(&self, id: &str) -> Result<(), Error> {
-        let mut instances = self.instances.lock().await;
-        let inst = instances.get_mut(id).ok_or(Error::InstanceNotFound(id.to_string()))?;
+        let instances = self.instances.lock().await.clone();
+        let inst = instances.get(id).ok_or(Error::InstanceNotFound(id.to_string()))?;
 
-        inst.last_con = Arc::new(Mutex::new(Some(chrono::Utc::now().naive_utc())));
+        *inst.last_con.lock().await = Some(chrono::Utc::now().naive_utc());
+
+        if *inst.status.lock().await == InstanceStatus::Unresponsive {
+            info!("Instance {} is responsive again", id);
+
+            self.set_inst_status_in(id, inst, InstanceStatus::Running).await?;
+        }
 
         Ok(())
     }
+    /// Records startup progress reported by the host, moving the instance
+    /// to `Creating` until the host reports that it's ready
+    pub async fn set_progress(&self, id: &str, progress: HostProgress) -> Result<(), Error> {
+        let instances = self.instances.lock().await.clone();
+        let inst = instances.get(id).ok_or(Error::InstanceNotFound(id.to_string()))?;
+
+        // Late reports from a host being stopped mustn't bring the instance
+        // back. Unresponsive containers are still running.
+        let current_status = inst.status.lock().await.clone();
+
+        if !matches!(
+            current_status,
+            InstanceStatus::Starting | InstanceStatus::Creating(_) | InstanceStatus::Running | InstanceStatus::Unresponsive,
+        ) {
+            return Err(Error::NotRunning);
+        }
+
+        let status = match progress.phase {
+            HostPhase::Ready => InstanceStatus::Running,
+            _ => InstanceStatus::Creating(progress.percentage.unwrap_or(0).min(100)),
+        };
+
+        debug!("Instance {} reported progress: {:?}", id, progress);
+
+        // Progress reports also count as check-ins
+        *inst.last_con.lock().await = Some(chrono::Utc::now().naive_utc());
+        *inst.progress.lock().await = Some(progress);
+
+        self.set_inst_status_in(id, inst, status).await
+    }
+    /// Stores records shipped by the host and passes them on to the event
+    /// stream
+    pub async fn push_logs(&self, id: &str, records: Vec<LogRecord>) -> Result<(), Error> {
+        let instances = self.instances.lock().await.clone();
+        let inst = instances.get(id).ok_or(Error::InstanceNotFound(id.to_string()))?;
+
+        let logs_config = self.config.lock().await.config.host_logs.clone();
+
+        log::push(&mut *inst.logs.lock().await, &records, logs_config.buffer_size);
+
+        if let Some(directory) = &logs_config.directory {
+            log::append_file(directory, id, &records).await?;
+        }
+
+        let _ = self.g_event_tx.send(GlobalEvent::HostLog { id: id.to_string(), records });
+
+        Ok(())
+    }
+    /// Returns the most recent buffered host log records, oldest first
+    pub async fn get_logs(&self, id: &str, limit: Option<usize>) -> Option<Vec<LogRecord>> {
+        let inst = self.instances.lock().await.get(id)?.clone();
+
+        let logs = inst.logs.lock().await;
+        let skip = logs.len().saturating_sub(limit.unwrap_or(logs.len()));
+
+        Some(logs.iter().skip(skip).cloned().collect())
+    }
     async fn start_host(&self, id: impl std::fmt::Display) -> Result<(), Error> {
         let id = id.to_string();
 
@@ -245,7 +748,21 @@ impl DockerInstanceProvider {
         let inst = instances.get_mut(&id).ok_or(Error::InstanceNotFound(id.clone()))?;
 
         self.set_inst_status_in(&id, inst, InstanceStatus::Starting).await?;
-        
+
+        // Containers created from an outdated definition or environment
+        // are replaced
+        let outdated = *inst.needs_recreate.lock().await || env_stale(inst).await;
+
+        if outdated && inst.container_id.lock().await.is_some() {
+            info!("Recreating container for instance {}", id);
+
+            if let Err(e) = self.delete_container(&id, inst).await {
+                self.set_inst_status_in(&id, inst, InstanceStatus::Inactive).await?;
+
+                return Err(e);
+            }
+        }
+
         let mut previous_created = false;
         loop {
             let container_id = inst.container_id.lock().await.clone();
@@ -291,11 +808,14 @@ impl DockerInstanceProvider {
             break;
         };
 
-        self.set_inst_status_in(&id, inst, InstanceStatus::Running).await?;
+        // The host may have already reported its own progress
+        if *inst.status.lock().await == InstanceStatus::Starting {
+            self.set_inst_status_in(&id, inst, InstanceStatus::Running).await?;
+        }
 
         Ok(())
     }
-    async fn stop_host(&self, id: impl std::fmt::Display) -> Result<(), Error> {
+    async fn stop_host(&self, id: impl std::fmt::Display, reason: StopReason) -> Result<(), Error> {
         let id = id.to_string();
 
         let mut instances = self.instances.lock().await.clone();
@@ -308,15 +828,93 @@ impl DockerInstanceProvider {
         let container_id = inst.container_id.lock().await.clone();
 
         if let Some(container_id) = container_id {
-            self.docker_handle.stop_container(&container_id, None::<container::StopContainerOptions>).await.map_err(Error::Docker)?;
+            let stop = inst.stop.lock().await.clone();
+
+            self.stop_container(&container_id, &stop).await?;
+        } else {
+            error!("No container attached to instance {}", id);
+        };
+
+        *inst.last_stop_reason.lock().await = Some(reason);
+
+        self.set_inst_status_in(&id, inst, InstanceStatus::Inactive).await?;
+
+        Ok(())
+    }
+    async fn kill_host(&self, id: impl std::fmt::Display) -> Result<(), Error> {
+        let id = id.to_string();
+
+        let instances = self.instances.lock().await.clone();
+        let inst = instances.get(&id).ok_or(Error::InstanceNotFound(id.clone()))?;
+
+        // Keeps the background loop from treating the exit as a crash
+        let previous_status = inst.status.lock().await.clone();
+
+        if previous_status != InstanceStatus::Inactive {
+            self.set_inst_status_in(&id, inst, InstanceStatus::Stopping).await?;
+        }
+
+        let container_id = inst.container_id.lock().await.clone();
+
+        if let Some(container_id) = container_id {
+            info!("Killing container of instance {}", id);
+
+            let kill_r = self.docker_handle.kill_container(&container_id, None::<container::KillContainerOptions<String>>).await;
+
+            if let Err(e) = kill_r {
+                self.set_inst_status_in(&id, inst, previous_status).await?;
+
+                return Err(Error::Docker(e));
+            }
         } else {
             error!("No container attached to instance {}", id);
         };
 
+        *inst.last_stop_reason.lock().await = Some(StopReason::Killed);
+
         self.set_inst_status_in(&id, inst, InstanceStatus::Inactive).await?;
 
         Ok(())
     }
+    /// Stops the container, writing the console command first if one is
+    /// set. The container is killed if it's still running after the timeout.
+    async fn stop_container(&self, container_id: &str, stop: &StopSettings) -> Result<(), Error> {
+        let command = match &stop.console_command {
+            Some(o) => o,
+            None => {
+                debug!("Stopping container {} with a {} second timeout", container_id, stop.timeout_secs);
+
+                return self.docker_handle.stop_container(container_id, Some(container::StopContainerOptions {
+                    t: stop.timeout_secs as i64,
+                })).await.map_err(Error::Docker);
+            }
+        };
+
+        debug!("Sending stop command to container {}", container_id);
+
+        let mut attached = self.docker_handle.attach_container(container_id, Some(container::AttachContainerOptions::<String> {
+            stdin: Some(true),
+            stream: Some(true),
+            ..Default::default()
+        })).await.map_err(Error::Docker)?;
+
+        attached.input.write_all(format!("{}\n", command).as_bytes()).await.map_err(Error::Io)?;
+        attached.input.flush().await.map_err(Error::Io)?;
+
+        let mut wait = self.docker_handle.wait_container(container_id, None::<container::WaitContainerOptions<String>>);
+
+        let timeout = tokio::time::Duration::from_secs(stop.timeout_secs);
+
+        if tokio::time::timeout(timeout, wait.next()).await.is_err() {
+            info!("Container {} didn't stop within {} seconds, killing it", container_id, stop.timeout_secs);
+
+            self.docker_handle.kill_container(container_id, None::<container::KillContainerOptions<String>>)
+                .await
+                .map_err(Error::Docker)?;
+        }
+
+        Ok(())
+    }
     async fn create_container(
         &self,
         id: impl std::fmt::Display,
@@ -324,15 +922,54 @@ impl DockerInstanceProvider {
     ) -> Result<String, Error> {
         let mut container_id_lock = inst.container_id.lock().await;
 
+        let config = self.config.lock().await.config.clone();
+
+        let id = id.to_string();
+
+        let runner_network = match network::network_name(&config.docker, &id) {
+            Some(name) => {
+                let instance_id = match config.docker.network {
+                    NetworkScope::Instance => Some(id.as_str()),
+                    _ => None,
+                };
+
+                Some(network::ensure(&self.docker_handle, &name, config.docker.isolate_instances, instance_id).await?)
+            }
+            None => None,
+        };
+
+        // The gateway address is only handed out when requested, the
+        // container can otherwise still use `host.docker.internal`
+        let gateway_network = match config.docker.runner_access {
+            RunnerAccess::Bridge => runner_network.as_ref(),
+            _ => None,
+        };
+
+        let extra_hosts = match config.docker.runner_access {
+            RunnerAccess::HostGateway => Some(vec![format!("{}:host-gateway", RUNNER_HOST)]),
+            _ => None,
+        };
+
+        let user_env = inst.env.lock().await.clone();
+
+        let mut env = env::to_docker(&user_env);
+        env.push(format!("TOKEN={}", inst.host_com_token.lock().await));
+        env.push(format!("RUNNER_URL={}", get_runner_addr(&config, gateway_network).await));
+
         let container_r = self.docker_handle.create_container(Some(CreateContainerOptions {
             name: new_container_name().await,
             ..Default::default()
         }), container::Config {
             image: Some(HOST_IMAGE),
-            env: Some(vec![
-                &format!("TOKEN={}", inst.host_com_token.lock().await),
-                &format!("RUNNER_URL={}", get_runner_addr(self.config.lock().await.config.port).await),
-            ]),
+            // Keeps stdin open so stop commands can be written to the console
+            open_stdin: Some(true),
+            env: Some(env.iter().map(String::as_str).collect()),
+            host_config: Some(HostConfig {
+                binds: host_api_binds(&config),
+                extra_hosts,
+                network_mode: runner_network.as_ref().map(|n| n.name.clone()),
+                ..Default::default()
+            }),
             ..Default::default()
         }).await.map_err(Error::Docker)?;
 
@@ -341,13 +978,11 @@ impl DockerInstanceProvider {
         }
 
         *container_id_lock = Some(container_r.id.clone());
+        *inst.needs_recreate.lock().await = false;
+        *inst.container_env_digest.lock().await = Some(env::digest(&user_env));
 
-        self.storage.lock().await.update_instance(id.to_string(), StoredInstance {
-            name: inst.name.lock().await.clone(),
-            inst_type: inst.inst_type.lock().await.clone(),
-            host_com_token: inst.host_com_token.lock().await.clone(),
-            container_id: container_id_lock.clone(),
-        }).await.map_err(Error::Storage)?;
+        self.storage.lock().await.update_instance(id, to_stored_instance(inst, container_id_lock.clone()).await)
+            .await.map_err(Error::Storage)?;
 
         Ok(container_r.id)
     }
@@ -363,7 +998,9 @@ impl DockerInstanceProvider {
 
         if c.state.ok_or(Error::NoContainerState)?.running.unwrap_or(false) {
             debug!("Stopping container {} for deletion...", container_id);
-            self.docker_handle.stop_container(&container_id, None::<container::StopContainerOptions>).await.map_err(Error::Docker)?;
+            let stop = inst.stop.lock().await.clone();
+
+            self.stop_container(&container_id, &stop).await?;
         }
 
         debug!("Deleting container {}...", container_id);
@@ -374,13 +1011,10 @@ impl DockerInstanceProvider {
         ).await.map_err(Error::Docker)?;
 
         *container_id_lock = None;
+        *inst.container_env_digest.lock().await = None;
 
-        self.storage.lock().await.update_instance(id.to_string(), StoredInstance {
-            name: inst.name.lock().await.clone(),
-            inst_type: inst.inst_type.lock().await.clone(),
-            host_com_token: inst.host_com_token.lock().await.clone(),
-            container_id: None,
-        }).await.map_err(Error::Storage)?;
+        self.storage.lock().await.update_instance(id.to_string(), to_stored_instance(inst, None).await)
+            .await.map_err(Error::Storage)?;
 
         Ok(())
     }
@@ -390,14 +1024,86 @@ impl DockerInstanceProvider {
 
         *inst.status.lock().await = status;
 
-        let _ = self.g_event_tx.send(GlobalEvent::ModifyInstance { id: id.to_string(), instance: to_pub_instance(inst).await });
+        let _ = self.g_event_tx.send(GlobalEvent::ModifyInstance { id: id.to_string(), instance: Box::new(to_pub_instance(inst).await) });
 
         Ok(())
     }
     async fn set_inst_status_in(&self, id: impl std::fmt::Display, inst: &Instance, status: InstanceStatus) -> Result<(), Error> {
-        *inst.status.lock().await = status;
+        {
+            let mut status_lock = inst.status.lock().await;
 
-        let _ = self.g_event_tx.send(GlobalEvent::ModifyInstance { id: id.to_string(), instance: to_pub_instance(inst).await });
+            let was_running = matches!(*status_lock, InstanceStatus::Running | InstanceStatus::Unresponsive);
+
+            if status == InstanceStatus::Running && !was_running {
+                *inst.running_since.lock().await = Some(chrono::Utc::now().naive_utc());
+            }
+            if status == InstanceStatus::Inactive {
+                *inst.progress.lock().await = None;
+            }
+
+            *status_lock = status;
+        }
+
+        let _ = self.g_event_tx.send(GlobalEvent::ModifyInstance { id: id.to_string(), instance: Box::new(to_pub_instance(inst).await) });
+
+        Ok(())
+    }
+    /// Flags a running or creating instance as unresponsive once its host
+    /// misses the heartbeat timeout, restarting the container if configured
+    /// to
+    async fn check_heartbeat(&self, id: &str, inst: &Instance, container_id: &str) -> Result<(), Error> {
+        let status = inst.status.lock().await.clone();
+
+        if !matches!(status, InstanceStatus::Running | InstanceStatus::Unresponsive | InstanceStatus::Creating(_)) {
+            return Ok(());
+        }
+
+        let heartbeat_config = self.config.lock().await.config.heartbeat.clone();
+
+        let last_con = *inst.last_con.lock().await;
+        let running_since = *inst.running_since.lock().await;
+
+        // Hosts which haven't checked in yet are timed from when the
+        // instance started running
+        let last_seen = match (last_con, running_since) {
+            (Some(a), Some(b)) => a.max(b),
+            (a, b) => match a.or(b) {
+                Some(o) => o,
+                None => return Ok(()),
+            },
+        };
+
+        let missed = (seconds_since(last_seen) / heartbeat_config.timeout_secs.max(1)) as u32;
+
+        if missed == 0 {
+            return Ok(());
+        }
+
+        if matches!(status, InstanceStatus::Running | InstanceStatus::Creating(_)) {
+            info!("Instance {} is unresponsive, no heartbeat in {} seconds", id, seconds_since(last_seen));
+
+            self.set_inst_status_in(id, inst, InstanceStatus::Unresponsive).await?;
+
+            let _ = self.g_event_tx.send(GlobalEvent::HostUnresponsive {
+                id: id.to_string(),
+                last_heartbeat_age: last_con.map(seconds_since),
+                missed,
+            });
+        }
+
+        if let Some(restart_after) = heartbeat_config.restart_after_misses {
+            if missed >= restart_after {
+                info!("Restarting unresponsive instance {} after {} missed heartbeats", id, missed);
+
+                self.docker_handle.restart_container(container_id, None::<container::RestartContainerOptions>)
+                    .await
+                    .map_err(Error::Docker)?;
+
+                // Give the host a full set of windows to check in again
+                *inst.running_since.lock().await = Some(chrono::Utc::now().naive_utc());
+                *inst.last_con.lock().await = None;
+            }
+        }
 
         Ok(())
     }
@@ -412,6 +1118,10 @@ impl DockerInstanceProvider {
                         error!("Error in background loop: {}", e);
                     }
                 };
+
+                if provider.shutdown.is_cancelled() {
+                    break;
+                }
             }
         });
 
@@ -421,7 +1131,10 @@ impl DockerInstanceProvider {
     }
     async fn bg_loop(&self) -> Result<(), Error> {
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_millis(INSTANCE_CHECK_INTERVAL_MS)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(INSTANCE_CHECK_INTERVAL_MS)) => {},
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
 
             debug!("Checking instances");
 
@@ -454,15 +1167,24 @@ impl DockerInstanceProvider {
                             debug!("Container {} found", container_id);
                             let c = self.docker_handle.inspect_container(&container_id, None).await.map_err(Error::Docker)?;
 
-                            if c.state.and_then(|s| s.running).unwrap_or(false) {
+                            if c.state.as_ref().and_then(|s| s.running).unwrap_or(false) {
                                 debug!("Container {} running", container_id);
                                 if *inst.status.lock().await == InstanceStatus::Inactive {
                                     self.set_inst_status_in(&id, inst, InstanceStatus::Running).await?;
                                 }
+
+                                self.check_heartbeat(id, inst, &container_id).await?;
+                                self.check_stable(id, inst).await;
                             } else {
                                 debug!("Container {} not running", container_id);
 
-                                if *inst.status.lock().await == InstanceStatus::Running {
+                                let status = inst.status.lock().await.clone();
+
+                                if matches!(status, InstanceStatus::Running | InstanceStatus::Unresponsive | InstanceStatus::Creating(_)) {
+                                    let exit_code = c.state.as_ref().and_then(|s| s.exit_code).unwrap_or(-1);
+
+                                    self.handle_exit(id, inst, exit_code).await;
+
                                     self.set_inst_status_in(&id, inst, InstanceStatus::Inactive).await?;
                                 }
                             };
@@ -470,23 +1192,90 @@ impl DockerInstanceProvider {
                         None => {
                             error!("Container {} not found (was attached to instance: {})", container_id, id);
                             *inst.container_id.lock().await = None;
+                            *inst.container_env_digest.lock().await = None;
                             
                             self.set_inst_status_in(&id, inst, InstanceStatus::Inactive).await?;
 
-                            self.storage.lock().await.update_instance(id.clone(), StoredInstance {
-                                name: inst.name.lock().await.clone(),
-                                inst_type: inst.inst_type.lock().await.clone(),
-                                host_com_token: inst.host_com_token.lock().await.clone(),
-                                container_id: None,
-                            }).await.map_err(Error::Storage)?;
+                            self.storage.lock().await.update_instance(id.clone(), to_stored_instance(inst, None).await)
+                                .await.map_err(Error::Storage)?;
                         }
                     };
                 } else {
                     debug!("No container attached to instance {}", id);
                 };
+
+                self.check_restart(id, inst).await;
             };
         };
     }
+    /// Records an unexpected container exit and schedules a restart if the
+    /// instance's policy allows it
+    async fn handle_exit(&self, id: &str, inst: &Instance, exit_code: i64) {
+        let crash_count = {
+            let mut crash_count = inst.crash_count.lock().await;
+            *crash_count += 1;
+            *crash_count
+        };
+
+        *inst.last_exit_code.lock().await = Some(exit_code);
+        *inst.last_stop_reason.lock().await = Some(match exit_code {
+            0 => StopReason::Exited,
+            _ => StopReason::Crashed,
+        });
+
+        info!("Instance {} exited unexpectedly with code {}", id, exit_code);
+
+        let delay = inst.restart_policy.lock().await.restart_delay(exit_code, crash_count);
+
+        match delay {
+            Some(delay) => {
+                info!("Restarting instance {} in {} seconds", id, delay.as_secs());
+
+                let delay = chrono::Duration::from_std(delay).unwrap_or_default();
+
+                *inst.restart_at.lock().await = Some(chrono::Utc::now().naive_utc() + delay);
+            }
+            None => debug!("Restart policy of instance {} doesn't allow a restart", id),
+        };
+    }
+    /// Resets the crash count once the instance has been running long
+    /// enough, so only consecutive crashes count towards `max_retries`
+    async fn check_stable(&self, id: &str, inst: &Instance) {
+        if *inst.status.lock().await != InstanceStatus::Running {
+            return;
+        }
+
+        let stable = inst.running_since.lock().await
+            .is_some_and(|t| seconds_since(t) >= restart::STABLE_RUN_SECS);
+
+        let mut crash_count = inst.crash_count.lock().await;
+
+        if stable && *crash_count > 0 {
+            debug!("Instance {} is running stably, resetting its crash count", id);
+
+            *crash_count = 0;
+        }
+    }
+    /// Starts the instance once a scheduled automatic restart is due
+    async fn check_restart(&self, id: &str, inst: &Instance) {
+        let due = inst.restart_at.lock().await
+            .is_some_and(|t| t <= chrono::Utc::now().naive_utc());
+
+        if !due || *inst.status.lock().await != InstanceStatus::Inactive {
+            return;
+        }
+
+        *inst.restart_at.lock().await = None;
+
+        let provider = self.clone();
+        let id = id.to_string();
+
+        self.tasks.spawn(async move {
+            if let Err(e) = provider.start_host(&id).await {
+                error!("Error automatically restarting instance {}: {}", id, e);
+            }
+        });
+    }
 }
 
 async fn to_pub_instance(inst: &Instance) -> PubInstance {
@@ -494,9 +1283,84 @@ async fn to_pub_instance(inst: &Instance) -> PubInstance {
         name: inst.name.lock().await.clone(),
         inst_type: inst.inst_type.lock().await.clone(),
         status: inst.status.lock().await.clone(),
+        variables: inst.variables.lock().await.clone(),
+        env: env::to_pub(&*inst.env.lock().await),
+        env_stale: env_stale(inst).await,
+        last_heartbeat_age: inst.last_con.lock().await.map(seconds_since),
+        progress: inst.progress.lock().await.clone(),
+        restart_policy: inst.restart_policy.lock().await.clone(),
+        crash_count: *inst.crash_count.lock().await,
+        last_exit_code: *inst.last_exit_code.lock().await,
+        stop: inst.stop.lock().await.clone(),
+        last_stop_reason: *inst.last_stop_reason.lock().await,
+        needs_recreate: *inst.needs_recreate.lock().await,
+    }
+}
+
+/// `container_id` is passed separately as callers may be holding its lock
+async fn to_stored_instance(inst: &Instance, container_id: Option<String>) -> StoredInstance {
+    StoredInstance {
+        name: inst.name.lock().await.clone(),
+        inst_type: inst.inst_type.lock().await.clone(),
+        host_com_token: inst.host_com_token.lock().await.clone(),
+        container_id,
+        restart_policy: inst.restart_policy.lock().await.clone(),
+        stop: inst.stop.lock().await.clone(),
+        needs_recreate: *inst.needs_recreate.lock().await,
+        variables: inst.variables.lock().await.clone(),
+        env: inst.env.lock().await.clone(),
+        container_env_digest: inst.container_env_digest.lock().await.clone(),
+    }
+}
+
+/// Stores a changed type or variables, marking an existing container to be
+/// recreated since the host only reads its definition when the container is
+/// first started
+async fn apply_definition(inst: &Instance, inst_type: Option<InstanceType>, variables: Option<Variables>) {
+    // Read before locking the definition, as `create_container` locks these
+    // in the opposite order
+    let has_container = inst.container_id.lock().await.is_some();
+
+    let mut changed = false;
+
+    if let Some(inst_type) = inst_type {
+        let mut inst_type_lock = inst.inst_type.lock().await;
+
+        if *inst_type_lock != inst_type {
+            *inst_type_lock = inst_type;
+            changed = true;
+        }
+    }
+
+    if let Some(variables) = variables {
+        let mut variables_lock = inst.variables.lock().await;
+
+        if *variables_lock != variables {
+            *variables_lock = variables;
+            changed = true;
+        }
+    }
+
+    if changed && has_container {
+        *inst.needs_recreate.lock().await = true;
     }
 }
 
+/// The digest is only set while a container exists, so the container lock
+/// isn't needed here
+async fn env_stale(inst: &Instance) -> bool {
+    let applied = inst.container_env_digest.lock().await.clone();
+
+    match applied {
+        Some(applied) => applied != env::digest(&*inst.env.lock().await),
+        None => false,
+    }
+}
+
+fn seconds_since(t: chrono::NaiveDateTime) -> u64 {
+    (chrono::Utc::now().naive_utc() - t).num_seconds().max(0) as u64
+}
+
 async fn unique_token(keys: Vec<String>) -> Result<String, Error> {
     for _ in 0..MAX_TOKEN_GEN_ITER {
         let new_id: String = rand::thread_rng()
@@ -527,8 +1391,48 @@ async fn new_container_name() -> String {
     format!("vk-{}", rand_name_suffix)
 }
 
-async fn get_runner_addr(port: u16) -> String {
-    format!("{}{}", RUNNER_ADDR_PREFIX, port)
+/// Returns the URL host containers use to reach the host API, through the
+/// runner-managed network if one is used
+async fn get_runner_addr(config: &Config, runner_network: Option<&network::RunnerNetwork>) -> String {
+    let host_api = &config.host_api;
+
+    // The gateway of a runner-managed network always reaches the runner,
+    // while `host.docker.internal` needs a `host-gateway` entry on Linux
+    let default_host = match runner_network {
+        Some(n) => n.gateway.clone(),
+        None => RUNNER_HOST.to_string(),
+    };
+
+    if let Some(address) = &host_api.address {
+        let host = match address.parse::<IpAddr>() {
+            Ok(a) if a.is_unspecified() => default_host,
+            Ok(IpAddr::V6(a)) => format!("[{}]", a),
+            _ => address.clone(),
+        };
+
+        return format!("http://{}:{}", host, host_api.port);
+    }
+
+    if let Some(socket_name) = host_api.socket_path.as_ref().and_then(|p| p.file_name()) {
+        return format!("unix://{}", host_api.container_socket_dir.join(socket_name).display());
+    }
+
+    // TLS requires a separate listener, so the main one is plain HTTP here
+    format!("http://{}:{}", default_host, config.port)
+}
+
+/// Mounts the host API socket's directory into host containers, if the
+/// host API is served over a Unix socket
+fn host_api_binds(config: &Config) -> Option<Vec<String>> {
+    let host_api = &config.host_api;
+
+    if host_api.address.is_some() {
+        return None;
+    }
+
+    let socket_dir = host_api.socket_path.as_ref()?.parent()?;
+
+    Some(vec![format!("{}:{}:ro", socket_dir.display(), host_api.container_socket_dir.display())])
 }
 
 #[cfg(test)]
@@ -544,6 +1448,77 @@ mod tests {
         assert_eq!(token.len(), 64);
     }
     #[tokio::test]
+    async fn test_get_runner_addr() {
+        let mut config = Config::default();
+
+        assert_eq!(get_runner_addr(&config, None).await, "http://host.docker.internal:56088");
+
+        let runner_network = network::RunnerNetwork {
+            name: "volkanicmc".to_string(),
+            gateway: "172.18.0.1".to_string(),
+        };
+
+        assert_eq!(get_runner_addr(&config, Some(&runner_network)).await, "http://172.18.0.1:56088");
+
+        config.host_api.address = Some("0.0.0.0".to_string());
+
+        assert_eq!(get_runner_addr(&config, None).await, "http://host.docker.internal:56089");
+        assert_eq!(get_runner_addr(&config, Some(&runner_network)).await, "http://172.18.0.1:56089");
+
+        config.host_api.address = Some("172.17.0.1".to_string());
+
+        assert_eq!(get_runner_addr(&config, None).await, "http://172.17.0.1:56089");
+
+        config.host_api.address = None;
+        config.host_api.socket_path = Some("/run/vk/runner.sock".into());
+
+        assert_eq!(get_runner_addr(&config, None).await, "unix:///run/volkanicmc/runner.sock");
+    }
+    #[tokio::test]
+    async fn test_apply_definition() {
+        let inst = Instance {
+            name: Arc::new(Mutex::new("test".to_string())),
+            inst_type: Arc::new(Mutex::new(InstanceType::Volkanic { source: VolkanicSource::Base64(String::new()) })),
+            status: Arc::new(Mutex::new(InstanceStatus::Inactive)),
+            host_com_token: Arc::new(Mutex::new(String::new())),
+            last_con: Arc::new(Mutex::new(None)),
+            running_since: Arc::new(Mutex::new(None)),
+            progress: Arc::new(Mutex::new(None)),
+            logs: Arc::new(Mutex::new(VecDeque::new())),
+            restart_policy: Arc::new(Mutex::new(restart::RestartPolicy::default())),
+            crash_count: Arc::new(Mutex::new(0)),
+            last_exit_code: Arc::new(Mutex::new(None)),
+            restart_at: Arc::new(Mutex::new(None)),
+            stop: Arc::new(Mutex::new(StopSettings::default())),
+            last_stop_reason: Arc::new(Mutex::new(None)),
+            needs_recreate: Arc::new(Mutex::new(false)),
+            variables: Arc::new(Mutex::new(Variables::new())),
+            env: Arc::new(Mutex::new(EnvVars::new())),
+            container_env_digest: Arc::new(Mutex::new(None)),
+            container_id: Arc::new(Mutex::new(None)),
+        };
+
+        let variables = Variables::from([("motd".to_string(), serde_jsonc::Value::from("Hi"))]);
+
+        // Without a container there's nothing to recreate
+        apply_definition(&inst, None, Some(variables.clone())).await;
+
+        assert!(!*inst.needs_recreate.lock().await);
+
+        *inst.container_id.lock().await = Some("container".to_string());
+
+        apply_definition(&inst, None, Some(variables)).await;
+
+        assert!(!*inst.needs_recreate.lock().await);
+
+        let variables = Variables::from([("motd".to_string(), serde_jsonc::Value::from("Bye"))]);
+
+        apply_definition(&inst, None, Some(variables.clone())).await;
+
+        assert!(*inst.needs_recreate.lock().await);
+        assert_eq!(*inst.variables.lock().await, variables);
+    }
+    #[tokio::test]
     async fn test_new_container_name() {
         let name = new_container_name().await;
 
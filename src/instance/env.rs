@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{sha256_hex, Error};
+
+/// Set by the runner itself, so can't be overridden
+const RESERVED_NAMES: &[&str] = &["TOKEN", "RUNNER_URL"];
+
+pub type EnvVars = HashMap<String, EnvVar>;
+
+/// A user-defined environment variable of the host container
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct EnvVar {
+    pub value: String,
+    /// Hides the value from the API
+    #[serde(default)]
+    pub secret: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PubEnvVar {
+    /// Not included for secrets
+    pub value: Option<String>,
+    pub secret: bool,
+}
+
+pub fn to_pub(env: &EnvVars) -> HashMap<String, PubEnvVar> {
+    env.iter()
+        .map(|(name, var)| (name.clone(), PubEnvVar {
+            value: match var.secret {
+                true => None,
+                false => Some(var.value.clone()),
+            },
+            secret: var.secret,
+        }))
+        .collect()
+}
+
+pub fn validate(env: &EnvVars) -> Result<(), Error> {
+    for name in env.keys() {
+        validate_name(name)?;
+    }
+
+    Ok(())
+}
+
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    let mut chars = name.chars();
+
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+        return Err(Error::InvalidEnv(format!("\"{}\" is not a valid variable name", name)));
+    }
+    if RESERVED_NAMES.contains(&name) {
+        return Err(Error::InvalidEnv(format!("\"{}\" is set by the runner", name)));
+    }
+
+    Ok(())
+}
+
+/// Digest of the variables, used to tell whether a container was created
+/// with the current set
+pub fn digest(env: &EnvVars) -> String {
+    let mut lines: Vec<String> = env.iter()
+        .map(|(name, var)| format!("{}={}\n", name, var.value))
+        .collect();
+
+    lines.sort();
+
+    sha256_hex(lines.concat().as_bytes())
+}
+
+/// `NAME=value` pairs as passed to Docker
+pub fn to_docker(env: &EnvVars) -> Vec<String> {
+    env.iter()
+        .map(|(name, var)| format!("{}={}", name, var.value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env() {
+        assert!(validate_name("JAVA_OPTS").is_ok());
+        assert!(validate_name("_x1").is_ok());
+        assert!(validate_name("1X").is_err());
+        assert!(validate_name("A-B").is_err());
+        assert!(validate_name("TOKEN").is_err());
+
+        let env = EnvVars::from([
+            ("A".to_string(), EnvVar { value: "1".to_string(), secret: false }),
+            ("B".to_string(), EnvVar { value: "hunter2".to_string(), secret: true }),
+        ]);
+
+        let public = to_pub(&env);
+
+        assert_eq!(public["A"].value.as_deref(), Some("1"));
+        assert_eq!(public["B"].value, None);
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, path::{Path, PathBuf}};
+use tokio::{fs, io::AsyncWriteExt};
+
+use super::Error;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum LogLevel {
+    #[serde(rename = "trace")]
+    Trace,
+    #[serde(rename = "debug")]
+    Debug,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+}
+
+/// A structured log record shipped by a host
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Time the record was produced, defaults to when it was received
+    #[serde(default = "Utc::now")]
+    pub timestamp: DateTime<Utc>,
+    pub level: LogLevel,
+    /// Part of the host the record came from, e.g. `build` or `launch`
+    #[serde(default)]
+    pub target: Option<String>,
+    pub message: String,
+}
+
+/// Appends records to the buffer, dropping the oldest once it holds more
+/// than `capacity`
+pub fn push(buffer: &mut VecDeque<LogRecord>, records: &[LogRecord], capacity: usize) {
+    buffer.extend(records.iter().cloned());
+
+    let excess = buffer.len().saturating_sub(capacity);
+    buffer.drain(..excess);
+}
+
+/// Appends records to the instance's log file as JSON lines
+pub async fn append_file(directory: &Path, id: &str, records: &[LogRecord]) -> Result<(), Error> {
+    fs::create_dir_all(directory).await.map_err(Error::Io)?;
+
+    let mut lines = String::new();
+
+    for record in records {
+        lines.push_str(&serde_jsonc::to_string(record).map_err(Error::JsonEncode)?);
+        lines.push('\n');
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path(directory, id))
+        .await
+        .map_err(Error::Io)?;
+
+    file.write_all(lines.as_bytes()).await.map_err(Error::Io)
+}
+
+pub async fn remove_file(directory: &Path, id: &str) -> Result<(), Error> {
+    match fs::remove_file(file_path(directory, id)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+fn file_path(directory: &Path, id: &str) -> PathBuf {
+    directory.join(format!("{}.jsonl", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_bounded() {
+        let record = |message: &str| LogRecord {
+            timestamp: Utc::now(),
+            level: LogLevel::Info,
+            target: None,
+            message: message.to_string(),
+        };
+
+        let mut buffer = VecDeque::new();
+
+        push(&mut buffer, &[record("a"), record("b")], 3);
+        push(&mut buffer, &[record("c"), record("d")], 3);
+
+        let messages: Vec<&str> = buffer.iter().map(|r| r.message.as_str()).collect();
+
+        assert_eq!(messages, vec!["b", "c", "d"]);
+    }
+}
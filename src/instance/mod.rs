@@ -1,16 +1,23 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{broadcast, Mutex};
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
+use tokio::sync::Mutex;
 use tracing::error;
 use uuid::Uuid;
 
-use crate::storage;
+use crate::{storage, template};
 
 mod docker;
+mod env;
+mod log;
+mod network;
+mod restart;
 mod volkanic;
 
 pub use docker::DockerInstanceProvider;
-pub use volkanic::VolkanicSource;
+pub use env::{EnvVar, EnvVars, PubEnvVar};
+pub use log::LogRecord;
+pub use restart::RestartPolicy;
+pub use volkanic::{sha256_hex, VolkanicSource};
 
 /// Maximum allowed number of attempts to generate a unique UUID for
 /// a new instance.
@@ -21,8 +28,6 @@ const MAX_UUID_GEN_ITER: usize = 128;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("{0}")]
-    Generic(String),
     #[error("Docker error: {0}")]
     Docker(bollard::errors::Error),
     #[error("Storage error: {0}")]
@@ -35,6 +40,30 @@ pub enum Error {
     ContainerIdNotFound,
     #[error("No container state")]
     NoContainerState,
+    #[error("No gateway address found for Docker network: {0}")]
+    NoNetworkGateway(String),
+    #[error("Instance isn't starting or running")]
+    NotRunning,
+    #[error("Instance is busy: {0}")]
+    InstanceBusy(String),
+    #[error("Invalid instance name")]
+    InvalidName,
+    #[error("Invalid environment variable: {0}")]
+    InvalidEnv(String),
+    #[error("Invalid template source: {0}")]
+    InvalidSource(String),
+    #[error("Invalid template ({} issues found)", .0.len())]
+    InvalidTemplate(Vec<template::TemplateIssue>),
+    #[error("Template library error: {0}")]
+    Template(template::Error),
+    #[error("Template fetch error: {0}")]
+    Fetch(reqwest::Error),
+    #[error("Template digest mismatch (expected {expected}, got {actual})")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("JSON encode error: {0}")]
+    JsonEncode(serde_jsonc::Error),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -43,6 +72,28 @@ pub struct PubInstance {
     #[serde(rename = "type")]
     pub inst_type: InstanceType,
     pub status: InstanceStatus,
+    /// Values for the variables declared by the template
+    pub variables: Variables,
+    pub env: HashMap<String, PubEnvVar>,
+    /// The container was created with different environment variables
+    /// than are currently set, so it's recreated the next time the
+    /// instance starts
+    pub env_stale: bool,
+    /// Seconds since the host last checked in
+    pub last_heartbeat_age: Option<u64>,
+    /// Latest progress reported by the host while it's starting up
+    pub progress: Option<HostProgress>,
+    pub restart_policy: RestartPolicy,
+    /// Consecutive unexpected exits since the instance was last started,
+    /// stopped or restarted through the API
+    pub crash_count: u32,
+    pub last_exit_code: Option<i64>,
+    pub stop: StopSettings,
+    /// Why the instance last stopped
+    pub last_stop_reason: Option<StopReason>,
+    /// Settings baked into the container have changed since it was
+    /// created, so it's recreated the next time the instance starts
+    pub needs_recreate: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -51,6 +102,61 @@ pub struct StoredInstance {
     pub inst_type: InstanceType,
     pub host_com_token: String,
     pub container_id: Option<String>,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub stop: StopSettings,
+    #[serde(default)]
+    pub needs_recreate: bool,
+    #[serde(default)]
+    pub variables: Variables,
+    #[serde(default)]
+    pub env: EnvVars,
+    /// Digest of the environment variables the container was created with
+    #[serde(default)]
+    pub container_env_digest: Option<String>,
+}
+
+/// How an instance's server is shut down when it's stopped
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct StopSettings {
+    /// Seconds the server is given to shut down before it's killed
+    pub timeout_secs: u64,
+    /// Command written to the server console to stop it, instead of sending
+    /// a termination signal, e.g. `stop`
+    pub console_command: Option<String>,
+}
+
+impl Default for StopSettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60,
+            console_command: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum StopReason {
+    /// Stopped through the API
+    #[serde(rename = "requested")]
+    Requested,
+    /// Stopped to be started again
+    #[serde(rename = "restart")]
+    Restart,
+    /// Force-killed through the API
+    #[serde(rename = "killed")]
+    Killed,
+    /// The server exited on its own with a zero exit code
+    #[serde(rename = "exited")]
+    Exited,
+    /// The server exited on its own with a non-zero exit code
+    #[serde(rename = "crashed")]
+    Crashed,
+    /// Stopped as the runner shut down
+    #[serde(rename = "shutdown")]
+    Shutdown,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -73,6 +179,59 @@ pub enum InstanceStatus {
     Starting,
     #[serde(rename = "stopping")]
     Stopping,
+    /// Container is running, but the host has stopped checking in
+    #[serde(rename = "unresponsive")]
+    Unresponsive,
+}
+
+/// A single instance along with details that are too costly to include
+/// in listings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InstanceDetail {
+    #[serde(flatten)]
+    pub instance: PubInstance,
+    pub container: Option<ContainerDetail>,
+    pub last_heartbeat: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ContainerDetail {
+    pub id: String,
+    pub image: Option<String>,
+    pub created: Option<String>,
+    pub ports: Vec<PortMapping>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PortMapping {
+    /// Port and protocol inside the container, e.g. `25565/tcp`
+    pub container_port: String,
+    pub host_ip: Option<String>,
+    pub host_port: Option<u16>,
+}
+
+/// Startup phase reported by the host container
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum HostPhase {
+    #[serde(rename = "preparing")]
+    Preparing,
+    #[serde(rename = "downloading-java")]
+    DownloadingJava,
+    #[serde(rename = "building")]
+    Building,
+    #[serde(rename = "starting")]
+    Starting,
+    /// The server is up and accepting players
+    #[serde(rename = "ready")]
+    Ready,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HostProgress {
+    pub phase: HostPhase,
+    /// Percentage of the current phase completed, from 0 to 100
+    pub percentage: Option<u8>,
+    pub message: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -82,10 +241,28 @@ struct Instance {
     pub status: Arc<Mutex<InstanceStatus>>,
     pub host_com_token: Arc<Mutex<String>>,
     pub last_con: Arc<Mutex<Option<chrono::NaiveDateTime>>>,
+    /// When the instance last became running, used in place of `last_con`
+    /// until the host first checks in
+    pub running_since: Arc<Mutex<Option<chrono::NaiveDateTime>>>,
+    pub progress: Arc<Mutex<Option<HostProgress>>>,
+    /// Most recent records shipped by the host
+    pub logs: Arc<Mutex<VecDeque<LogRecord>>>,
+    pub restart_policy: Arc<Mutex<RestartPolicy>>,
+    pub crash_count: Arc<Mutex<u32>>,
+    pub last_exit_code: Arc<Mutex<Option<i64>>>,
+    /// When a pending automatic restart is due
+    pub restart_at: Arc<Mutex<Option<chrono::NaiveDateTime>>>,
+    pub stop: Arc<Mutex<StopSettings>>,
+    pub last_stop_reason: Arc<Mutex<Option<StopReason>>>,
+    pub needs_recreate: Arc<Mutex<bool>>,
+    pub variables: Arc<Mutex<Variables>>,
+    pub env: Arc<Mutex<EnvVars>>,
+    pub container_env_digest: Arc<Mutex<Option<String>>>,
     pub container_id: Arc<Mutex<Option<String>>>,
 }
 
 pub type PubInstanceList = HashMap<String, PubInstance>;
+pub type Variables = HashMap<String, serde_jsonc::Value>;
 pub type StoredInstanceList = HashMap<String, StoredInstance>;
 type InstanceList = HashMap<String, Instance>;
 
@@ -94,6 +271,29 @@ pub struct InstanceRequest {
     pub name: String,
     #[serde(rename = "type")]
     pub inst_type: InstanceType,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub stop: StopSettings,
+    #[serde(default)]
+    pub variables: Variables,
+    #[serde(default)]
+    pub env: EnvVars,
+}
+
+/// Changes to an existing instance, fields left unset are kept as is
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct InstanceModification {
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub inst_type: Option<InstanceType>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub stop: Option<StopSettings>,
+    /// Replaces every variable value
+    pub variables: Option<Variables>,
+    /// Sets the given environment variables, removing those set to `null`.
+    /// Others are left as is, so secrets don't need to be sent again.
+    pub env: Option<HashMap<String, Option<EnvVar>>>,
 }
 
 async fn unique_id(keys: Vec<String>) -> Result<String, Error> {
@@ -0,0 +1,128 @@
+use bollard::{
+    errors::Error as DockerError,
+    network::{CreateNetworkOptions, ListNetworksOptions},
+    Docker,
+};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::config::{DockerConfig, NetworkScope, RunnerAccess};
+
+use super::Error;
+
+/// Label marking networks created by the runner
+const MANAGED_LABEL: &str = "me.bitzero.volkanicmc.managed";
+/// Label holding the ID of the instance a network was created for
+const INSTANCE_LABEL: &str = "me.bitzero.volkanicmc.instance";
+/// Bridge driver option controlling whether containers on the same network
+/// can reach each other
+const ICC_OPTION: &str = "com.docker.network.bridge.enable_icc";
+
+/// A bridge network owned by the runner
+#[derive(Debug, Clone)]
+pub struct RunnerNetwork {
+    pub name: String,
+    /// Address of the Docker host on the network, which host containers
+    /// use to reach the runner
+    pub gateway: String,
+}
+
+/// Returns the name of the runner-managed network the instance's container
+/// should be attached to, or `None` for Docker's default network
+pub fn network_name(config: &DockerConfig, instance_id: &str) -> Option<String> {
+    match config.network {
+        NetworkScope::Instance => Some(instance_network_name(config, instance_id)),
+        NetworkScope::Runner => Some(config.network_name.clone()),
+        NetworkScope::Default => match config.runner_access {
+            // The gateway address is only known on a runner-managed network
+            RunnerAccess::Bridge => Some(config.network_name.clone()),
+            _ => None,
+        },
+    }
+}
+
+pub fn instance_network_name(config: &DockerConfig, instance_id: &str) -> String {
+    format!("{}-{}", config.network_name, instance_id)
+}
+
+/// Returns the network with the given name, creating it if it doesn't
+/// exist yet
+pub async fn ensure(
+    docker: &Docker,
+    name: &str,
+    isolate: bool,
+    instance_id: Option<&str>,
+) -> Result<RunnerNetwork, Error> {
+    let network = match docker.inspect_network::<String>(name, None).await {
+        Ok(o) => {
+            let icc_disabled = o.options.as_ref()
+                .and_then(|opts| opts.get(ICC_OPTION))
+                .is_some_and(|v| v == "false");
+
+            if icc_disabled != isolate {
+                warn!("Docker network {} was created with different isolation settings, delete it to apply the current settings", name);
+            }
+
+            o
+        }
+        Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => {
+            info!("Creating Docker network {}", name);
+
+            let mut labels = HashMap::from([(MANAGED_LABEL, "true")]);
+            if let Some(id) = instance_id {
+                labels.insert(INSTANCE_LABEL, id);
+            }
+
+            let mut options = HashMap::new();
+            if isolate {
+                options.insert(ICC_OPTION, "false");
+            }
+
+            docker.create_network(CreateNetworkOptions {
+                name,
+                driver: "bridge",
+                labels,
+                options,
+                ..Default::default()
+            }).await.map_err(Error::Docker)?;
+
+            docker.inspect_network::<String>(name, None).await.map_err(Error::Docker)?
+        }
+        Err(e) => return Err(Error::Docker(e)),
+    };
+
+    let gateway = network.ipam
+        .and_then(|i| i.config)
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|c| c.gateway)
+        .ok_or(Error::NoNetworkGateway(name.to_string()))?;
+
+    Ok(RunnerNetwork {
+        name: name.to_string(),
+        gateway,
+    })
+}
+
+/// Removes every runner-managed network created for the instance. Found by
+/// label rather than name, since the naming settings may have changed since
+/// the networks were created.
+pub async fn remove_instance_networks(docker: &Docker, instance_id: &str) -> Result<(), Error> {
+    let managed_filter = format!("{}=true", MANAGED_LABEL);
+    let instance_filter = format!("{}={}", INSTANCE_LABEL, instance_id);
+
+    let networks = docker.list_networks(Some(ListNetworksOptions {
+        filters: HashMap::from([("label", vec![managed_filter.as_str(), instance_filter.as_str()])]),
+    })).await.map_err(Error::Docker)?;
+
+    for name in networks.into_iter().filter_map(|n| n.name) {
+        info!("Removing Docker network {}", name);
+
+        match docker.remove_network(&name).await {
+            Ok(_) | Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => {}
+            Err(e) => return Err(Error::Docker(e)),
+        }
+    }
+
+    Ok(())
+}
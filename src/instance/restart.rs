@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Upper bound for the delay between automatic restarts
+const MAX_BACKOFF_SECS: u64 = 300;
+/// Seconds an instance has to stay running before its crashes are no
+/// longer counted as consecutive
+pub const STABLE_RUN_SECS: u64 = 300;
+
+fn default_backoff_secs() -> u64 {
+    5
+}
+
+/// What the provider does when an instance's container exits without
+/// being asked to
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum RestartPolicy {
+    #[default]
+    #[serde(rename = "never")]
+    Never,
+    /// Restart after non-zero exits, giving up after `max_retries`
+    /// consecutive crashes
+    #[serde(rename = "on-failure")]
+    OnFailure {
+        max_retries: u32,
+        #[serde(default = "default_backoff_secs")]
+        backoff_secs: u64,
+    },
+    #[serde(rename = "always")]
+    Always {
+        #[serde(default = "default_backoff_secs")]
+        backoff_secs: u64,
+    },
+}
+
+impl RestartPolicy {
+    /// Returns how long to wait before restarting after `crash_count`
+    /// consecutive unexpected exits, or `None` if the instance should stay
+    /// stopped. The delay doubles with each consecutive exit.
+    pub fn restart_delay(&self, exit_code: i64, crash_count: u32) -> Option<Duration> {
+        let backoff_secs = match self {
+            RestartPolicy::Never => return None,
+            RestartPolicy::OnFailure { max_retries, backoff_secs } => {
+                if exit_code == 0 || crash_count > *max_retries {
+                    return None;
+                }
+
+                *backoff_secs
+            }
+            RestartPolicy::Always { backoff_secs } => *backoff_secs,
+        };
+
+        let factor = 2u64.saturating_pow(crash_count.saturating_sub(1));
+
+        Some(Duration::from_secs(backoff_secs.saturating_mul(factor).min(MAX_BACKOFF_SECS)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_delay() {
+        let on_failure = RestartPolicy::OnFailure { max_retries: 2, backoff_secs: 5 };
+
+        assert_eq!(on_failure.restart_delay(1, 1), Some(Duration::from_secs(5)));
+        assert_eq!(on_failure.restart_delay(1, 2), Some(Duration::from_secs(10)));
+        assert_eq!(on_failure.restart_delay(1, 3), None);
+        assert_eq!(on_failure.restart_delay(0, 1), None);
+
+        let always = RestartPolicy::Always { backoff_secs: 100 };
+
+        assert_eq!(always.restart_delay(0, 1), Some(Duration::from_secs(100)));
+        assert_eq!(always.restart_delay(0, 40), Some(Duration::from_secs(MAX_BACKOFF_SECS)));
+
+        assert_eq!(RestartPolicy::Never.restart_delay(1, 1), None);
+    }
+}
@@ -1,7 +1,251 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::{fs, sync::Mutex};
+use tracing::{debug, info, warn};
+
+use crate::{config::TemplatesConfig, template::TemplateStore};
+
+use super::Error;
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub enum VolkanicSource {
     #[serde(rename = "base64")]
     Base64(String),
+    /// Fetched by the runner, and checked against `sha256` if set
+    #[serde(rename = "url")]
+    Url {
+        url: String,
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+    /// From the runner's template library. Pinned to the latest version
+    /// when the instance is created if no version is given.
+    #[serde(rename = "template")]
+    Template {
+        name: String,
+        #[serde(default)]
+        version: Option<u32>,
+    },
+}
+
+/// Templates fetched from URLs, kept in memory and optionally on disk
+///
+/// Pinned templates are cached by digest and never fetched again. Unpinned
+/// templates are fetched each time, falling back to the last copy if the
+/// fetch fails.
+#[derive(Debug, Clone)]
+pub struct SourceCache {
+    client: reqwest::Client,
+    memory: Arc<Mutex<MemoryCache>>,
+}
+
+/// Evicts the oldest template once full
+#[derive(Debug, Default)]
+struct MemoryCache {
+    templates: HashMap<String, Vec<u8>>,
+    order: VecDeque<String>,
+}
+
+impl SourceCache {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            memory: Arc::new(Mutex::new(MemoryCache::default())),
+        }
+    }
+    /// Returns the raw template from the source
+    pub async fn resolve(
+        &self,
+        source: &VolkanicSource,
+        config: &TemplatesConfig,
+        templates: &TemplateStore,
+    ) -> Result<Vec<u8>, Error> {
+        let (url, sha256) = match source {
+            VolkanicSource::Base64(base64) => {
+                use base64::Engine;
+
+                return base64::engine::general_purpose::STANDARD.decode(base64)
+                    .map_err(|e| Error::InvalidSource(e.to_string()));
+            }
+            VolkanicSource::Url { url, sha256 } => {
+                let sha256 = sha256.as_ref().map(|s| s.to_lowercase());
+
+                // The digest names the cache file, so nothing else is allowed
+                if let Some(sha256) = &sha256 {
+                    if !is_sha256_hex(sha256) {
+                        return Err(Error::InvalidSource("sha256 must be 64 hexadecimal characters".to_string()));
+                    }
+                }
+
+                (url, sha256)
+            }
+            VolkanicSource::Template { name, version } => {
+                return templates.get(name, *version).await
+                    .map(|(_, data)| data)
+                    .map_err(Error::Template);
+            }
+        };
+
+        let key = match &sha256 {
+            Some(sha256) => sha256.clone(),
+            None => url_key(url),
+        };
+
+        if let Some(sha256) = &sha256 {
+            if let Some(data) = self.cached(&key, config).await {
+                if &sha256_hex(&data) == sha256 {
+                    debug!("Using cached template for {}", url);
+
+                    return Ok(data);
+                }
+
+                warn!("Cached template for {} doesn't match its digest, fetching again", url);
+            }
+        }
+
+        let data = match self.fetch(url, config).await {
+            Ok(o) => o,
+            Err(e) if sha256.is_none() => match self.cached(&key, config).await {
+                Some(data) => {
+                    warn!("Unable to fetch template from {}, using cached copy: {}", url, e);
+
+                    return Ok(data);
+                }
+                None => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+
+        if let Some(expected) = sha256 {
+            let actual = sha256_hex(&data);
+
+            if actual != expected {
+                return Err(Error::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        self.store(&key, &data, config).await;
+
+        Ok(data)
+    }
+    async fn fetch(&self, url: &str, config: &TemplatesConfig) -> Result<Vec<u8>, Error> {
+        info!("Fetching template from {}", url);
+
+        let mut response = self.client.get(url)
+            .timeout(std::time::Duration::from_secs(config.fetch_timeout_secs))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(Error::Fetch)?;
+
+        let too_large = || Error::InvalidSource(format!("template is larger than {} bytes", config.max_size_bytes));
+
+        if response.content_length().is_some_and(|l| l > config.max_size_bytes) {
+            return Err(too_large());
+        }
+
+        // The length header is optional, so the body is checked as it arrives
+        let mut data = Vec::new();
+
+        while let Some(chunk) = response.chunk().await.map_err(Error::Fetch)? {
+            if (data.len() + chunk.len()) as u64 > config.max_size_bytes {
+                return Err(too_large());
+            }
+
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(data)
+    }
+    async fn cached(&self, key: &str, config: &TemplatesConfig) -> Option<Vec<u8>> {
+        if let Some(data) = self.memory.lock().await.templates.get(key) {
+            return Some(data.clone());
+        }
+
+        let path = cache_path(config, key)?;
+
+        fs::read(path).await.ok()
+    }
+    async fn store(&self, key: &str, data: &[u8], config: &TemplatesConfig) {
+        self.memory.lock().await.insert(key, data, config.memory_cache_size);
+
+        if let Some(path) = cache_path(config, key) {
+            if let Some(parent) = path.parent() {
+                let _ = fs::create_dir_all(parent).await;
+            }
+
+            if let Err(e) = fs::write(&path, data).await {
+                warn!("Unable to write template cache file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl MemoryCache {
+    fn insert(&mut self, key: &str, data: &[u8], capacity: usize) {
+        if self.templates.insert(key.to_string(), data.to_vec()).is_none() {
+            self.order.push_back(key.to_string());
+        }
+
+        while self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.templates.remove(&oldest);
+            }
+        }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Unpinned templates are cached under a digest of their URL
+fn url_key(url: &str) -> String {
+    format!("url-{}", sha256_hex(url.as_bytes()))
+}
+
+fn cache_path(config: &TemplatesConfig, key: &str) -> Option<PathBuf> {
+    config.cache_dir.as_ref().map(|d| d.join(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StorageConfig};
+
+    #[tokio::test]
+    async fn test_resolve_base64() {
+        let cache = SourceCache::new();
+        let config = TemplatesConfig::default();
+        let templates = TemplateStore::new(&Config {
+            storage: StorageConfig { path: Some("store.json".into()) },
+            ..Default::default()
+        }).unwrap();
+
+        let data = cache.resolve(&VolkanicSource::Base64("eyJhIjoxfQ==".to_string()), &config, &templates).await.unwrap();
+
+        assert_eq!(data, b"{\"a\":1}");
+
+        let traversal = VolkanicSource::Url { url: "http://localhost".to_string(), sha256: Some("../../etc/passwd".to_string()) };
+
+        assert!(matches!(cache.resolve(&traversal, &config, &templates).await, Err(Error::InvalidSource(_))));
+    }
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        );
+        assert!(is_sha256_hex(&sha256_hex(b"abc")));
+        assert!(!is_sha256_hex("abc"));
+    }
 }
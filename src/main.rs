@@ -1,44 +1,82 @@
 // #![deny(warnings)]
 #![forbid(unsafe_code)]
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::{
     path::PathBuf,
     sync::{Arc, atomic::{AtomicBool, Ordering}},
 };
-use tokio::sync::{broadcast, Mutex};
-use tracing::{debug, info, error};
+use tokio::sync::Mutex;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, error, warn};
 
 mod config;
 mod global_event;
 mod instance;
 mod net;
 mod storage;
+mod template;
+mod webhook;
 
 const DEBUG_MODE_VAR: &str = "VK_DEBUG";
+/// Seconds the HTTP servers are given to close once shutdown begins
+const SERVER_CLOSE_TIMEOUT_SECS: u64 = 15;
 
 #[derive(Debug, Parser)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
-    pub config_path: PathBuf,
-    #[arg(short = 'a', long)]
-    pub address: Option<String>,
-    #[arg(short = 'p', long)]
-    pub port: Option<u16>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(required = true)]
+    pub config_path: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: config::Overrides,
+    /// Print the effective config, with all overrides applied, and exit
+    #[arg(long)]
+    pub print_config: bool,
     #[arg(long)]
     pub add_latency: Option<u16>,
 }
 
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Validate the config, reporting every problem found, and exit
+    CheckConfig {
+        config_path: PathBuf,
+        #[command(flatten)]
+        overrides: config::Overrides,
+    },
+}
+
 #[derive(Clone)]
 struct AppState {
-    pub g_event_tx: broadcast::Sender<global_event::GlobalEvent>,
+    pub g_event_tx: global_event::EventBus,
     pub instances: Arc<Mutex<instance::DockerInstanceProvider>>,
     pub add_latency: Option<u16>,
+    pub webhooks: webhook::WebhookDispatcher,
+    /// Cancelled once the runner starts shutting down
+    pub shutdown: CancellationToken,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
+    if let Some(Command::CheckConfig { config_path, overrides }) = args.command {
+        check_config(config_path, overrides).await;
+
+        return;
+    }
+
+    // Always present without a subcommand, as enforced by `clap`
+    let config_path = args.config_path.clone().unwrap_or_default();
+
+    if args.print_config {
+        print_config(config_path, args.overrides).await;
+
+        return;
+    }
+
     let debug_mode = std::env::var(DEBUG_MODE_VAR) == Ok(String::from("true"));
 
     tracing_subscriber::fmt()
@@ -55,8 +93,35 @@ async fn main() {
         r.store(false, Ordering::SeqCst);
     }).unwrap();
 
+    let shutdown = CancellationToken::new();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+
+        async move {
+            wait_for_signal().await;
+
+            info!("Shutting down...");
+
+            running.store(false, Ordering::SeqCst);
+            shutdown.cancel();
+
+            wait_for_signal().await;
+
+            warn!("Received second signal, exiting immediately");
+
+            std::process::exit(1);
+        }
+    });
+
+    run(config_path, args, shutdown).await;
+
+    info!("Shutdown complete");
+}
+
+async fn wait_for_signal() {
     #[cfg(unix)]
-    let shutdown = async {
+    {
         let mut interrupt = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt()).unwrap();
         let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
 
@@ -64,29 +129,18 @@ async fn main() {
             _ = interrupt.recv() => debug!("Received SIGINT"),
             _ = terminate.recv() => info!("Received termination signal"),
         }
-    };
+    }
 
     #[cfg(windows)]
-    let shutdown = async {
+    {
         tokio::signal::ctrl_c().await.unwrap();
         debug!("Received Ctrl+C");
-    };
-
-    tokio::select! {
-        _ = shutdown => {
-            running.store(false, Ordering::SeqCst);
-        },
-        _ = run(args) => {},
-    };
-
-    info!("Shutting down...");
-
-    std::process::exit(0);
+    }
 }
 
-async fn run(args: Args) {
+async fn run(config_path: PathBuf, args: Args, shutdown: CancellationToken) {
     let app_config = Arc::new(Mutex::new(
-        match config::ConfigFile::new(args.config_path.clone()).await {
+        match config::ConfigFile::new(config_path, args.overrides.clone()).await {
             Ok(o) => o,
             Err(e) => {
                 error!("Config error: {}", e);
@@ -95,10 +149,31 @@ async fn run(args: Args) {
         }
     ));
 
-    let address = args.address.clone().unwrap_or(app_config.lock().await.config.address.clone());
-    let port = args.port.unwrap_or(app_config.lock().await.config.port);
+    if let Err(e) = app_config.lock().await.validate().await {
+        error!("Config error: {}", e);
+        std::process::exit(1);
+    }
+
+    let _config_watch_handle = config::watch(app_config.clone()).await;
+
+    let address = app_config.lock().await.config.address.clone();
+    let port = app_config.lock().await.config.port;
 
-    let g_event_tx = global_event::init_channel();
+    let events_config = app_config.lock().await.config.events.clone();
+
+    let g_event_tx = match global_event::EventBus::new(&events_config).await {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Event history error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Drained by the instance provider on shutdown
+    let tasks = TaskTracker::new();
+
+    let webhooks = webhook::WebhookDispatcher::new(app_config.clone(), shutdown.clone(), tasks.clone());
+    let _webhooks_handle = webhooks.start(&g_event_tx);
 
     let storage_provider = Arc::new(Mutex::new(
         match storage::JsonStorageProvider::new(app_config.lock().await.config.clone()).await {
@@ -115,6 +190,8 @@ async fn run(args: Args) {
             app_config.clone(),
             g_event_tx.clone(),
             storage_provider.clone(),
+            shutdown.clone(),
+            tasks,
         ).await {
             Ok(o) => o,
             Err(e) =>  {
@@ -128,14 +205,119 @@ async fn run(args: Args) {
         g_event_tx,
         instances: instance_provider.clone(),
         add_latency: args.add_latency,
+        webhooks,
+        shutdown: shutdown.clone(),
     };
 
-    let http_handle = net::http::serve(address, port, app_state).await;
+    let tls_config = app_config.lock().await.config.tls.clone();
 
-    match http_handle.await {
-        Ok(_) => {},
+    let rustls_config = match tls_config.enabled {
+        true => match net::tls::load(&tls_config, &address).await {
+            Ok(o) => {
+                let _tls_watch_handle = net::tls::watch(o.clone(), app_config.clone()).await;
+
+                Some(o)
+            }
+            Err(e) => {
+                error!("TLS error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        false => None,
+    };
+
+    let host_api = app_config.lock().await.config.host_api.clone();
+    let separate_host_api = host_api.is_separate();
+
+    let http_handle = net::http::serve(address, port, rustls_config, !separate_host_api, app_state.clone()).await;
+
+    let host_handle = match separate_host_api {
+        true => Some(net::http::serve_host(host_api, app_state).await),
+        false => None,
+    };
+
+    let host_closed = async {
+        match host_handle {
+            Some(h) => h.await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let servers_closed = async {
+        tokio::select! {
+            r = http_handle => match r {
+                Ok(Err(e)) => error!("HTTP server error: {}", e),
+                Err(e) => error!("HTTP server error: {}", e),
+                Ok(Ok(_)) => {},
+            },
+            r = host_closed => match r {
+                Ok(Err(e)) => error!("Host API server error: {}", e),
+                Err(e) => error!("Host API server error: {}", e),
+                Ok(Ok(_)) => {},
+            },
+        };
+    };
+    tokio::pin!(servers_closed);
+
+    tokio::select! {
+        _ = &mut servers_closed => shutdown.cancel(),
+        _ = shutdown.cancelled() => {
+            let close_timeout = tokio::time::Duration::from_secs(SERVER_CLOSE_TIMEOUT_SECS);
+
+            if tokio::time::timeout(close_timeout, &mut servers_closed).await.is_err() {
+                warn!("HTTP server didn't close in time");
+            }
+        },
+    };
+
+    let shutdown_config = app_config.lock().await.config.shutdown.clone();
+
+    instance_provider.lock().await.shutdown(&shutdown_config).await;
+}
+
+async fn check_config(config_path: PathBuf, overrides: config::Overrides) {
+    if !config_path.is_file() {
+        eprintln!("Config file not found: {}", config_path.display());
+        std::process::exit(1);
+    }
+
+    let config_file = match config::ConfigFile::new(config_path, overrides).await {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match config_file.validate().await {
+        Ok(_) => println!("Config is valid"),
         Err(e) => {
-            error!("HTTP server error: {}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    };
+}
+
+async fn print_config(config_path: PathBuf, overrides: config::Overrides) {
+    // Loading a missing config would create it
+    if !config_path.is_file() {
+        eprintln!("Config file not found: {}", config_path.display());
+        std::process::exit(1);
     }
+
+    let config_file = match config::ConfigFile::new(config_path, overrides).await {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match serde_jsonc::to_string_pretty(&config_file.config.redacted()) {
+        Ok(o) => println!("{}", o),
+        Err(e) => {
+            eprintln!("Unable to encode config: {}", e);
+            std::process::exit(1);
+        }
+    };
 }
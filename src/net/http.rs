@@ -1,59 +1,92 @@
 use axum::{middleware, routing::{get, post}, Router};
+use axum_server::tls_rustls::RustlsConfig;
+#[cfg(unix)]
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+#[cfg(unix)]
+use std::path::PathBuf;
 use tokio::sync::oneshot;
+#[cfg(unix)]
+use tokio_util::sync::CancellationToken;
 use tower_http::trace::{self, TraceLayer};
-use tracing::{info, Level};
+use tracing::{debug, info, Level};
 
-use crate::AppState;
+use crate::{AppState, config::HostApiConfig};
 
 use super::routes;
 
 pub const PROTOCOL_VER: u32 = 1;
+/// Seconds open connections are given to finish once shutdown begins
+const GRACEFUL_SHUTDOWN_SECS: u64 = 10;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("HTTP I/O error: {0}")]
     Io(std::io::Error),
+    #[error("No separate listener configured for the host API")]
+    NoHostListener,
 }
 
-pub async fn serve(addr: String, port: u16, state: AppState) -> oneshot::Receiver<Result<(), Error>> {
+/// Serves the management API, over TLS if a config is provided. The host
+/// API is included unless it has its own listener.
+pub async fn serve(
+    addr: String,
+    port: u16,
+    tls: Option<RustlsConfig>,
+    include_host_api: bool,
+    state: AppState,
+) -> oneshot::Receiver<Result<(), Error>> {
     let (tx, rx) = oneshot::channel();
 
     tokio::spawn(async move {
         let tx = tx;
 
-        let app = Router::new()
-            .route("/", get(root))
-            .route("/check", get(routes::heartbeat::heartbeat))
-            .route("/info", get(routes::info::info))
-            .route("/auth", get(routes::auth::login))
-            .route("/events", get(routes::event::global_event_sub))
-            .route("/instance/list", get(routes::instance::get::list_instances))
-            .route("/instance/new", post(routes::instance::modify::new_instance))
-            .route("/instance/:id/delete", post(routes::instance::del::del_instance))
-            .route("/instance/:id/start", post(routes::instance::trigger_status::start_instance))
-            .route("/instance/:id/stop", post(routes::instance::trigger_status::stop_instance))
-            .route("/internal/host/auth", post(routes::host::auth))
-            .route("/internal/host/check", post(routes::host::heartbeat))
-            .route("/internal/host/def", get(routes::host::definition::get_def))
-            .layer(
-                TraceLayer::new_for_http()
-                    .on_request(trace::DefaultOnRequest::new()
-                        .level(Level::DEBUG)
-                    )
-                    .on_response(trace::DefaultOnResponse::new()
-                        .level(Level::DEBUG)
-                    )
-                    .on_failure(trace::DefaultOnFailure::new()
-                        .level(Level::ERROR)
-                    )
-            )
-            .layer(middleware::from_fn_with_state(state.clone(), super::middleware::latency))
-            .with_state(state);
+        let mut app = management_router();
+
+        if include_host_api {
+            app = app.merge(host_router());
+        }
+
+        let shutdown = state.shutdown.clone();
+
+        let app = with_layers(app, state);
 
         info!("Binding to {}:{}", addr, port);
 
         let listener = tokio::net::TcpListener::bind((addr, port)).await.unwrap();
-        let r = axum::serve(listener, app).await.map_err(Error::Io);
+
+        let r = match tls {
+            Some(tls) => {
+                info!("TLS enabled");
+
+                let handle = axum_server::Handle::new();
+
+                tokio::spawn({
+                    let handle = handle.clone();
+
+                    async move {
+                        shutdown.cancelled().await;
+                        handle.graceful_shutdown(Some(std::time::Duration::from_secs(GRACEFUL_SHUTDOWN_SECS)));
+                    }
+                });
+
+                match listener.into_std() {
+                    Ok(listener) => axum_server::from_tcp_rustls(listener, tls)
+                        .handle(handle)
+                        .serve(app.into_make_service())
+                        .await
+                        .map_err(Error::Io),
+                    Err(e) => Err(Error::Io(e)),
+                }
+            }
+            None => axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+                .map_err(Error::Io),
+        };
 
         info!("HTTP server closed");
 
@@ -63,6 +96,141 @@ pub async fn serve(addr: String, port: u16, state: AppState) -> oneshot::Receive
     rx
 }
 
+/// Serves the host API on its own listener, either TCP or a Unix socket
+pub async fn serve_host(host_api: HostApiConfig, state: AppState) -> oneshot::Receiver<Result<(), Error>> {
+    let (tx, rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let tx = tx;
+
+        let shutdown = state.shutdown.clone();
+
+        let app = with_layers(host_router(), state);
+
+        let r = match (host_api.address, host_api.socket_path) {
+            (Some(addr), _) => {
+                info!("Binding host API to {}:{}", addr, host_api.port);
+
+                match tokio::net::TcpListener::bind((addr, host_api.port)).await {
+                    Ok(listener) => axum::serve(listener, app)
+                        .with_graceful_shutdown(shutdown.cancelled_owned())
+                        .await
+                        .map_err(Error::Io),
+                    Err(e) => Err(Error::Io(e)),
+                }
+            }
+            #[cfg(unix)]
+            (None, Some(socket_path)) => {
+                info!("Binding host API to {}", socket_path.display());
+
+                serve_unix(socket_path, app, shutdown).await.map_err(Error::Io)
+            }
+            _ => Err(Error::NoHostListener),
+        };
+
+        info!("Host API server closed");
+
+        let _ = tx.send(r);
+    });
+
+    rx
+}
+
+fn management_router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(root))
+        .route("/check", get(routes::heartbeat::heartbeat))
+        .route("/info", get(routes::info::info))
+        .route("/auth", get(routes::auth::login))
+        .route("/events", get(routes::event::global_event_sub))
+        .route("/events/ws", get(routes::event_ws::ws_event_sub))
+        .route("/instance/list", get(routes::instance::get::list_instances))
+        .route("/instance/new", post(routes::instance::modify::new_instance))
+        .route("/instance/:id", get(routes::instance::get::get_instance).patch(routes::instance::modify::modify_instance))
+        .route("/instance/:id/delete", post(routes::instance::del::del_instance))
+        .route("/instance/:id/start", post(routes::instance::trigger_status::start_instance))
+        .route("/instance/:id/stop", post(routes::instance::trigger_status::stop_instance))
+        .route("/instance/:id/restart", post(routes::instance::trigger_status::restart_instance))
+        .route("/instance/:id/kill", post(routes::instance::trigger_status::kill_instance))
+        .route("/instance/:id/logs", get(routes::instance::log::get_logs))
+        .route("/instance/:id/events", get(routes::event::instance_event_sub))
+        .route("/instance/:id/upgrade-template", post(routes::instance::modify::upgrade_template))
+        .route("/template/list", get(routes::template::list_templates))
+        .route("/template/:name", get(routes::template::get_template))
+        .route("/template/:name/new", post(routes::template::new_template_version))
+        .route("/template/:name/delete", post(routes::template::del_template))
+        .route("/template/:name/:version", get(routes::template::get_template_version))
+        .route("/webhook/deliveries", get(routes::webhook::get_deliveries))
+}
+
+fn host_router() -> Router<AppState> {
+    Router::new()
+        .route("/internal/host/auth", post(routes::host::auth))
+        .route("/internal/host/check", post(routes::host::heartbeat))
+        .route("/internal/host/def", get(routes::host::definition::get_def))
+        .route("/internal/host/status", post(routes::host::status::post_status))
+        .route("/internal/host/log", post(routes::host::log::post_log))
+}
+
+fn with_layers(router: Router<AppState>, state: AppState) -> Router {
+    router
+        .layer(
+            TraceLayer::new_for_http()
+                .on_request(trace::DefaultOnRequest::new()
+                    .level(Level::DEBUG)
+                )
+                .on_response(trace::DefaultOnResponse::new()
+                    .level(Level::DEBUG)
+                )
+                .on_failure(trace::DefaultOnFailure::new()
+                    .level(Level::ERROR)
+                )
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), super::middleware::latency))
+        .with_state(state)
+}
+
+#[cfg(unix)]
+/// Accepts connections until shutdown begins, leaving open connections to
+/// finish on their own
+async fn serve_unix(socket_path: PathBuf, app: Router, shutdown: CancellationToken) -> Result<(), std::io::Error> {
+    // Remove the socket left behind by a previous run, but nothing else
+    if let Ok(metadata) = tokio::fs::symlink_metadata(&socket_path).await {
+        use std::os::unix::fs::FileTypeExt;
+
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", socket_path.display()),
+            ));
+        }
+
+        tokio::fs::remove_file(&socket_path).await?;
+    }
+    if let Some(parent) = socket_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+
+    loop {
+        let (socket, _) = tokio::select! {
+            r = listener.accept() => r?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let service = TowerToHyperService::new(app.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(socket), service)
+                .await
+            {
+                debug!("Host API connection error: {}", e);
+            }
+        });
+    }
+}
+
 async fn root() -> &'static str {
     "VolkanicMC Runner is active\n\nFor more details, check: https://github.com/8Bitz0/volkanicmc-runner"
 }
@@ -1,3 +1,4 @@
 pub mod http;
 mod middleware;
 mod routes;
+pub mod tls;
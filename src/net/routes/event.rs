@@ -1,14 +1,78 @@
 use async_stream::stream;
-use axum::{extract::State, response::sse::{Event, Sse}};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
 use futures_util::Stream;
-use tokio::sync::broadcast;
-use tracing::debug;
+use serde::Deserialize;
+use std::{collections::HashSet, time::Duration};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
 
-use crate::{AppState, global_event::GlobalEvent};
+use crate::{AppState, global_event::{EventFilter, GlobalEvent, Replay, StampedEvent}};
 
-pub async fn global_event_sub(State(state): State<AppState>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+/// Header set by `EventSource` when reconnecting
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+/// Seconds between keep-alive comments on idle streams, so proxies don't
+/// drop the connection
+const KEEP_ALIVE_SECS: u64 = 15;
+
+/// Comma-separated lists, each left unset to receive everything
+#[derive(Debug, Default, Deserialize)]
+pub struct EventQuery {
+    pub instances: Option<String>,
+    pub kinds: Option<String>,
+}
+
+impl EventQuery {
+    pub fn to_filter(&self) -> Result<EventFilter, (StatusCode, String)> {
+        let filter = EventFilter {
+            instances: self.instances.as_deref().map(split_list),
+            kinds: self.kinds.as_deref().map(split_list),
+        };
+
+        let unknown_kinds = filter.unknown_kinds();
+
+        if !unknown_kinds.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, format!("Unknown event kinds: {}", unknown_kinds.join(", "))));
+        }
+
+        Ok(filter)
+    }
+}
+
+pub async fn global_event_sub(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, String)> {
+    let filter = query.to_filter()?;
+
+    Ok(event_stream(state, &headers, filter))
+}
+
+/// Events about a single instance, which can still be filtered by kind
+pub async fn instance_event_sub(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<EventQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, String)> {
+    let mut filter = query.to_filter()?;
+
+    if state.instances.lock().await.get_instance(&id).await.is_none() {
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    }
+
+    filter.instances = Some(HashSet::from([id]));
+
+    Ok(event_stream(state, &headers, filter))
+}
+
+fn event_stream(state: AppState, headers: &HeaderMap, filter: EventFilter) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
     struct Guard {
-        g_event_rx: broadcast::Receiver<GlobalEvent>,
+        g_event_rx: broadcast::Receiver<StampedEvent>,
     }
 
     impl Drop for Guard {
@@ -19,19 +83,72 @@ pub async fn global_event_sub(State(state): State<AppState>) -> Sse<impl Stream<
 
     debug!("Client requested event listener");
 
-    let g_event_tx = state.g_event_tx.clone();
+    // Unparseable IDs are treated the same as a fresh connection
+    let last_id = headers.get(LAST_EVENT_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    let (replay, g_event_rx) = match last_id {
+        Some(last_id) => state.g_event_tx.subscribe_from(last_id),
+        None => (Replay::Events(Vec::new()), state.g_event_tx.subscribe()),
+    };
+
+    let shutdown = state.shutdown.clone();
 
     let stream = stream! {
         let mut guard = Guard {
-            g_event_rx: g_event_tx.subscribe(),
+            g_event_rx,
         };
-        
+
+        match replay {
+            Replay::Events(events) => {
+                for g_event in events.iter().filter(|e| filter.matches(&e.event)) {
+                    yield to_sse_event(g_event);
+                }
+            }
+            Replay::ResyncRequired { latest_id } => {
+                debug!("Client missed events no longer in the history");
+
+                yield to_sse_event(&StampedEvent { id: latest_id, event: GlobalEvent::ResyncRequired {} });
+            }
+        }
+
         loop {
-            let g_event = guard.g_event_rx.recv().await.unwrap();
+            // Streams end on shutdown so the server can close gracefully
+            let g_event = tokio::select! {
+                e = guard.g_event_rx.recv() => e,
+                _ = shutdown.cancelled() => break,
+            };
+
+            match g_event {
+                Ok(g_event) if filter.matches(&g_event.event) => yield to_sse_event(&g_event),
+                Ok(_) => {}
+                // Sent whatever the filter, since the skipped events can't
+                // be told apart. Has no ID, so a reconnecting client still
+                // replays from the last event it actually received.
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event listener lagged behind, skipping {} events", skipped);
 
-            yield Event::default().json_data(g_event);
+                    yield Event::default().json_data(GlobalEvent::ResyncRequired {});
+                }
+                Err(RecvError::Closed) => break,
+            }
         }
     };
 
-    Sse::new(stream)
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(KEEP_ALIVE_SECS)))
+}
+
+fn to_sse_event(g_event: &StampedEvent) -> Result<Event, axum::Error> {
+    Event::default()
+        .id(g_event.id.to_string())
+        .json_data(&g_event.event)
+}
+
+fn split_list(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
 }
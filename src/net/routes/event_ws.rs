@@ -0,0 +1,288 @@
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Query, State},
+    http::StatusCode,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, time::{Duration, Instant}};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::{AppState, global_event::{EventFilter, GlobalEvent, Replay, StampedEvent}};
+
+use super::event::EventQuery;
+
+/// Seconds between pings sent to the client
+const PING_INTERVAL_SECS: u64 = 15;
+/// Seconds without hearing from the client before the connection is closed
+const IDLE_TIMEOUT_SECS: u64 = 45;
+
+/// Sent by the client to change which events it receives at runtime
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ClientMessage {
+    /// Adds to the instances and kinds received. A connection without a
+    /// filter receives everything until it first subscribes.
+    #[serde(rename = "subscribe")]
+    Subscribe {
+        #[serde(default)]
+        instances: Vec<String>,
+        #[serde(default)]
+        kinds: Vec<String>,
+    },
+    /// Removes from the instances and kinds received. Removing the last
+    /// one of either leaves it empty, so nothing matches until `reset`.
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe {
+        #[serde(default)]
+        instances: Vec<String>,
+        #[serde(default)]
+        kinds: Vec<String>,
+    },
+    /// Clears the filter, receiving everything again
+    #[serde(rename = "reset")]
+    Reset {},
+}
+
+/// Resumes from the event after `since`, the last ID the client received
+#[derive(Debug, Default, Deserialize)]
+pub struct ResumeQuery {
+    pub since: Option<u64>,
+}
+
+/// Events are sent with the ID to resume from, which is left out for
+/// resyncs caused by the socket lagging behind
+#[derive(Debug, Serialize)]
+struct OutgoingEvent<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    event: &'a GlobalEvent,
+}
+
+/// Replies to client messages, sent alongside the events
+#[derive(Debug, Serialize)]
+enum ServerMessage {
+    /// The filter now in effect
+    #[serde(rename = "subscription")]
+    Subscription(EventFilter),
+    #[serde(rename = "error")]
+    Error(String),
+}
+
+/// Same events as `/events`, with the query string setting the initial filter
+///
+/// Like `/events`, a socket which lags behind is sent `resync-required`
+/// whatever its filter, since it can't tell which of the skipped events it
+/// wanted.
+pub async fn ws_event_sub(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+    Query(resume): Query<ResumeQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, String)> {
+    let filter = query.to_filter()?;
+
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, filter, resume.since)))
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState, mut filter: EventFilter, since: Option<u64>) {
+    debug!("Client opened event socket");
+
+    let (replay, mut g_event_rx) = match since {
+        Some(since) => state.g_event_tx.subscribe_from(since),
+        None => (Replay::Events(Vec::new()), state.g_event_tx.subscribe()),
+    };
+
+    let replayed = match replay {
+        Replay::Events(events) => events.into_iter()
+            .filter(|e| filter.matches(&e.event))
+            .collect(),
+        Replay::ResyncRequired { latest_id } => {
+            debug!("Client missed events no longer in the history");
+
+            vec![StampedEvent { id: latest_id, event: GlobalEvent::ResyncRequired {} }]
+        }
+    };
+
+    for g_event in replayed {
+        let Some(outgoing) = to_event_message(Some(g_event.id), &g_event.event) else {
+            continue;
+        };
+
+        if socket.send(outgoing).await.is_err() {
+            debug!("Client closed event socket");
+
+            return;
+        }
+    }
+
+    let mut ping_interval = tokio::time::interval(Duration::from_secs(PING_INTERVAL_SECS));
+    let mut last_seen = Instant::now();
+
+    loop {
+        let outgoing = tokio::select! {
+            e = g_event_rx.recv() => match e {
+                Ok(g_event) if filter.matches(&g_event.event) => to_event_message(Some(g_event.id), &g_event.event),
+                Ok(_) => continue,
+                // Has no ID, so a resuming client still replays from the
+                // last event it actually received
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event socket lagged behind, skipping {} events", skipped);
+
+                    to_event_message(None, &GlobalEvent::ResyncRequired {})
+                }
+                Err(RecvError::Closed) => break,
+            },
+            msg = socket.recv() => {
+                let msg = match msg {
+                    Some(Ok(o)) => o,
+                    Some(Err(e)) => {
+                        debug!("Event socket error: {}", e);
+
+                        break;
+                    }
+                    None => break,
+                };
+
+                last_seen = Instant::now();
+
+                match msg {
+                    Message::Text(text) => handle_message(&text, &mut filter),
+                    Message::Close(_) => break,
+                    // Pings are answered automatically
+                    _ => continue,
+                }
+            }
+            _ = ping_interval.tick() => {
+                if last_seen.elapsed() > Duration::from_secs(IDLE_TIMEOUT_SECS) {
+                    debug!("Closing unresponsive event socket");
+
+                    break;
+                }
+
+                Some(Message::Ping(Vec::new()))
+            }
+            // Sockets close on shutdown so the server can close gracefully
+            _ = state.shutdown.cancelled() => {
+                let _ = socket.send(Message::Close(None)).await;
+
+                break;
+            }
+        };
+
+        let Some(outgoing) = outgoing else {
+            continue;
+        };
+
+        if socket.send(outgoing).await.is_err() {
+            break;
+        }
+    }
+
+    debug!("Client closed event socket");
+}
+
+fn handle_message(text: &str, filter: &mut EventFilter) -> Option<Message> {
+    let reply = match serde_jsonc::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Subscribe { instances, kinds }) => {
+            let unknown_kinds = EventFilter { instances: None, kinds: Some(kinds.iter().cloned().collect()) }.unknown_kinds();
+
+            match unknown_kinds.is_empty() {
+                true => {
+                    add(&mut filter.instances, instances);
+                    add(&mut filter.kinds, kinds);
+
+                    ServerMessage::Subscription(filter.clone())
+                }
+                false => ServerMessage::Error(format!("Unknown event kinds: {}", unknown_kinds.join(", "))),
+            }
+        }
+        Ok(ClientMessage::Unsubscribe { instances, kinds }) => {
+            remove(&mut filter.instances, instances);
+            remove(&mut filter.kinds, kinds);
+
+            ServerMessage::Subscription(filter.clone())
+        }
+        Ok(ClientMessage::Reset {}) => {
+            *filter = EventFilter::default();
+
+            ServerMessage::Subscription(filter.clone())
+        }
+        Err(e) => ServerMessage::Error(format!("Invalid message: {}", e)),
+    };
+
+    to_message(&reply)
+}
+
+fn to_event_message(id: Option<u64>, event: &GlobalEvent) -> Option<Message> {
+    to_message(&OutgoingEvent { id, event })
+}
+
+fn to_message<T: Serialize>(value: &T) -> Option<Message> {
+    match serde_jsonc::to_string(value) {
+        Ok(o) => Some(Message::Text(o)),
+        Err(e) => {
+            warn!("Error encoding event socket message: {}", e);
+
+            None
+        }
+    }
+}
+
+fn add(set: &mut Option<HashSet<String>>, values: Vec<String>) {
+    if values.is_empty() {
+        return;
+    }
+
+    set.get_or_insert_with(HashSet::new).extend(values);
+}
+
+/// Unsubscribing while receiving everything has no effect. An emptied set
+/// is kept, so unsubscribing never widens the filter.
+fn remove(set: &mut Option<HashSet<String>>, values: Vec<String>) {
+    if let Some(set) = set {
+        for value in values {
+            set.remove(&value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_transitions() {
+        let mut filter = EventFilter::default();
+
+        handle_message(r#"{ "type": "subscribe", "instances": ["a", "b"], "kinds": ["host-log"] }"#, &mut filter);
+
+        assert_eq!(filter.instances, Some(HashSet::from(["a".to_string(), "b".to_string()])));
+        assert_eq!(filter.kinds, Some(HashSet::from(["host-log".to_string()])));
+
+        // Unknown kinds leave the filter as it was
+        handle_message(r#"{ "type": "subscribe", "kinds": ["unknown"] }"#, &mut filter);
+
+        assert_eq!(filter.kinds, Some(HashSet::from(["host-log".to_string()])));
+
+        handle_message(r#"{ "type": "unsubscribe", "instances": ["a"] }"#, &mut filter);
+
+        assert_eq!(filter.instances, Some(HashSet::from(["b".to_string()])));
+
+        handle_message(r#"{ "type": "unsubscribe", "instances": ["b"], "kinds": ["host-log"] }"#, &mut filter);
+
+        assert_eq!(filter.instances, Some(HashSet::new()));
+        assert_eq!(filter.kinds, Some(HashSet::new()));
+        assert!(!filter.matches(&GlobalEvent::DeleteInstance { id: "b".to_string() }));
+
+        handle_message(r#"{ "type": "reset" }"#, &mut filter);
+
+        assert_eq!(filter.instances, None);
+        assert_eq!(filter.kinds, None);
+
+        // Nothing to remove from while receiving everything
+        handle_message(r#"{ "type": "unsubscribe", "instances": ["c"] }"#, &mut filter);
+
+        assert_eq!(filter.instances, None);
+    }
+}
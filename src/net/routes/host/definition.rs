@@ -1,22 +1,24 @@
 use axum::{extract::State, Json,};
+use base64::Engine;
 use hyper::{HeaderMap, StatusCode};
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
-use crate::{AppState, instance::{InstanceType, VolkanicSource}};
+use crate::{
+    AppState,
+    instance::{sha256_hex, InstanceType, Variables, VolkanicSource},
+    template,
+};
 
 use super::get_host;
 
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error("HTTP error: {0}")]
-    Http(#[from] reqwest::Error),
-}
-
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct HostDefinition {
     #[serde(rename = "type")]
     pub i_type: HostInstanceType,
+    /// Values of the template's variables, with defaults filled in
+    #[serde(default)]
+    pub variables: Variables,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -24,35 +26,46 @@ pub enum HostInstanceType {
     #[serde(rename = "volkanic-construct")]
     VolkanicConstruct {
         base64: String,
+        /// Digest of the decoded construct
+        sha256: String,
+        /// Where the construct was fetched from, if it wasn't inline
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
     },
 }
 
-impl From<InstanceType> for HostInstanceType {
-    fn from(i_type: InstanceType) -> Self {
-        match i_type {
-            InstanceType::Volkanic { source } => {
-                match source {
-                    VolkanicSource::Base64(base64) => {
-                        HostInstanceType::VolkanicConstruct { base64 }
-                    }
-                }
-            }
-        }
-    }
-}
-
 pub async fn get_def(
     headers: HeaderMap,
     State(state): State<AppState>,
 ) -> Result<Json<HostDefinition>, StatusCode> {
     let instance_id = get_host(headers, state.clone()).await?;
 
-    let instance = state.instances.lock().await.get_instance(&instance_id).await
+    // Not holding the lock while a template is fetched
+    let provider = state.instances.lock().await.clone();
+
+    let instance = provider.get_instance(&instance_id).await
         .ok_or(StatusCode::UNAUTHORIZED)?;
 
-    let definition = HostDefinition {
-        i_type: instance.inst_type.clone().into()
+    let (i_type, variables) = match instance.inst_type {
+        InstanceType::Volkanic { source } => {
+            let data = provider.resolve_source(&source).await.map_err(|e| {
+                error!("Unable to resolve template for instance {}: {}", instance_id, e);
+
+                StatusCode::BAD_GATEWAY
+            })?;
+
+            let variables = template::resolve_values(&template::variables(&data), &instance.variables);
+
+            (HostInstanceType::VolkanicConstruct {
+                base64: base64::engine::general_purpose::STANDARD.encode(&data),
+                sha256: sha256_hex(&data),
+                url: match source {
+                    VolkanicSource::Url { url, .. } => Some(url),
+                    _ => None,
+                },
+            }, variables)
+        }
     };
 
-    Ok(Json(definition))
+    Ok(Json(HostDefinition { i_type, variables }))
 }
@@ -0,0 +1,35 @@
+use axum::{extract::State, Json};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, instance::LogRecord};
+
+use super::get_host;
+
+/// Maximum number of records accepted in a single batch
+const MAX_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogBatch {
+    pub records: Vec<LogRecord>,
+}
+
+pub async fn post_log(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(batch): Json<LogBatch>,
+) -> Result<StatusCode, StatusCode> {
+    let instance_id = get_host(headers, state.clone()).await?;
+
+    if batch.records.len() > MAX_BATCH_SIZE {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if batch.records.is_empty() {
+        return Ok(StatusCode::OK);
+    }
+
+    state.instances.lock().await.push_logs(&instance_id, batch.records).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::OK)
+}
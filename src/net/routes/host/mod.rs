@@ -4,6 +4,8 @@ use hyper::{HeaderMap, StatusCode};
 use crate::AppState;
 
 pub mod definition;
+pub mod log;
+pub mod status;
 
 pub async fn auth(
     headers: HeaderMap,
@@ -0,0 +1,26 @@
+use axum::{extract::State, Json};
+use hyper::{HeaderMap, StatusCode};
+
+use crate::{AppState, instance::{Error, HostProgress}};
+
+use super::get_host;
+
+pub async fn post_status(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(progress): Json<HostProgress>,
+) -> Result<StatusCode, StatusCode> {
+    let instance_id = get_host(headers, state.clone()).await?;
+
+    if progress.percentage.is_some_and(|p| p > 100) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    state.instances.lock().await.set_progress(&instance_id, progress).await
+        .map_err(|e| match e {
+            Error::NotRunning => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    Ok(StatusCode::OK)
+}
@@ -1,6 +1,6 @@
-use axum::{extract::State, Json, response::IntoResponse};
+use axum::{extract::{Path, State}, http::StatusCode, Json, response::IntoResponse};
 
-use crate::AppState;
+use crate::{AppState, instance::InstanceDetail};
 
 pub async fn list_instances(State(state): State<AppState>) -> impl IntoResponse {
     let instances_lock = state.instances.lock().await;
@@ -9,3 +9,14 @@ pub async fn list_instances(State(state): State<AppState>) -> impl IntoResponse
 
     Json(instances)
 }
+
+pub async fn get_instance(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<InstanceDetail>, StatusCode> {
+    let instances_lock = state.instances.lock().await;
+
+    instances_lock.get_instance_detail(&id).await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
@@ -0,0 +1,21 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
+use serde::Deserialize;
+
+use crate::{AppState, instance::LogRecord};
+
+#[derive(Debug, Deserialize)]
+pub struct LogQuery {
+    /// Only return this many of the most recent records
+    pub limit: Option<usize>,
+}
+
+pub async fn get_logs(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<LogQuery>,
+) -> Result<Json<Vec<LogRecord>>, StatusCode> {
+    let logs = state.instances.lock().await.get_logs(&id, query.limit).await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(logs))
+}
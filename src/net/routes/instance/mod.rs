@@ -1,4 +1,5 @@
 pub mod del;
 pub mod get;
+pub mod log;
 pub mod modify;
 pub mod trigger_status;
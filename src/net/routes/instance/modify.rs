@@ -1,22 +1,34 @@
 use axum::{
-    extract::State,
+    extract::{Path, State},
     http::StatusCode,
     Json,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
+use serde::Serialize;
 use tracing::{info, error};
 
 use crate::{
     AppState,
-    instance::InstanceRequest
+    instance::{Error, InstanceModification, InstanceRequest, PubInstance},
+    template::{self, TemplateIssue},
 };
 
 pub async fn new_instance(
     State(state): State<AppState>,
-    Json(payload): Json<InstanceRequest>,
-) -> impl IntoResponse {
+    Json(mut payload): Json<InstanceRequest>,
+) -> Response {
     info!("New instance requested");
 
+    // Not holding the lock while a template is fetched
+    let provider = state.instances.lock().await.clone();
+
+    if let Err(e) = provider.check_instance(&mut payload.inst_type, &payload.variables).await {
+        return error_response(e);
+    }
+    if let Err(e) = provider.check_env(&payload.env) {
+        return error_response(e);
+    }
+
     tokio::spawn(async move {
         let instances_lock = state.instances.lock().await;
         
@@ -33,5 +45,71 @@ pub async fn new_instance(
     (
         StatusCode::ACCEPTED,
         "",
-    )
+    ).into_response()
+}
+
+pub async fn modify_instance(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<InstanceModification>,
+) -> Result<Json<PubInstance>, Response> {
+    info!("Instance modification requested (\"{}\")", id);
+
+    let provider = state.instances.lock().await.clone();
+
+    provider.modify_instance(&id, payload).await
+        .map(Json)
+        .map_err(error_response)
+}
+
+pub async fn upgrade_template(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<PubInstance>, Response> {
+    info!("Template upgrade requested (\"{}\")", id);
+
+    let provider = state.instances.lock().await.clone();
+
+    provider.upgrade_template(&id).await
+        .map(Json)
+        .map_err(error_response)
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    issues: Vec<TemplateIssue>,
+}
+
+/// Client errors are described in the body, anything else is logged
+fn error_response(e: Error) -> Response {
+    let status = match &e {
+        Error::InstanceNotFound(_) => StatusCode::NOT_FOUND,
+        Error::InstanceBusy(_) => StatusCode::CONFLICT,
+        Error::InvalidName
+        | Error::InvalidEnv(_)
+        | Error::InvalidSource(_)
+        | Error::InvalidTemplate(_)
+        | Error::Fetch(_)
+        | Error::ChecksumMismatch { .. }
+        | Error::Template(template::Error::NotFound(_) | template::Error::VersionNotFound(_, _) | template::Error::InvalidName(_)) => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        _ => {
+            error!("Error modifying instance: {}", e);
+
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let body = ErrorBody {
+        error: e.to_string(),
+        issues: match e {
+            Error::InvalidTemplate(issues) => issues,
+            _ => Vec::new(),
+        },
+    };
+
+    (status, Json(body)).into_response()
 }
@@ -3,7 +3,6 @@ use axum::{
     http::StatusCode,
     response::IntoResponse
 };
-use tracing::error;
 
 use crate::AppState;
 
@@ -38,3 +37,35 @@ pub async fn stop_instance(
         "",
     )
 }
+
+pub async fn restart_instance(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    tokio::spawn(async move {
+        let instances_lock = state.instances.lock().await;
+
+        instances_lock.restart_instance(&id).await;
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        "",
+    )
+}
+
+pub async fn kill_instance(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    tokio::spawn(async move {
+        let instances_lock = state.instances.lock().await;
+
+        instances_lock.kill_instance(&id).await;
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        "",
+    )
+}
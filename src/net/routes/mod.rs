@@ -1,6 +1,9 @@
 pub mod auth;
 pub mod event;
+pub mod event_ws;
 pub mod heartbeat;
 pub mod host;
 pub mod info;
 pub mod instance;
+pub mod template;
+pub mod webhook;
@@ -0,0 +1,107 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    AppState,
+    template::{Error, TemplateInfo},
+};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TemplateUpload {
+    pub base64: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TemplateVersion {
+    pub name: String,
+    pub version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base64: Option<String>,
+}
+
+pub async fn list_templates(State(state): State<AppState>) -> Result<Json<Vec<TemplateInfo>>, StatusCode> {
+    let templates = state.instances.lock().await.templates();
+
+    templates.list().await.map(Json).map_err(to_status)
+}
+
+pub async fn get_template(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<TemplateInfo>, StatusCode> {
+    let templates = state.instances.lock().await.templates();
+
+    templates.info(&name).await.map(Json).map_err(to_status)
+}
+
+pub async fn get_template_version(
+    State(state): State<AppState>,
+    Path((name, version)): Path<(String, u32)>,
+) -> Result<Json<TemplateVersion>, StatusCode> {
+    let templates = state.instances.lock().await.templates();
+
+    let (version, data) = templates.get(&name, Some(version)).await.map_err(to_status)?;
+
+    Ok(Json(TemplateVersion {
+        name,
+        version,
+        base64: Some(base64::engine::general_purpose::STANDARD.encode(data)),
+    }))
+}
+
+/// Stores the upload as the template's next version
+pub async fn new_template_version(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(payload): Json<TemplateUpload>,
+) -> Result<(StatusCode, Json<TemplateVersion>), StatusCode> {
+    let data = base64::engine::general_purpose::STANDARD.decode(&payload.base64)
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+
+    let templates = state.instances.lock().await.templates();
+
+    let version = templates.add_version(&name, &data).await.map_err(to_status)?;
+
+    info!("Template {} version {} added", name, version);
+
+    Ok((StatusCode::CREATED, Json(TemplateVersion {
+        name,
+        version,
+        base64: None,
+    })))
+}
+
+pub async fn del_template(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let instances_lock = state.instances.lock().await;
+
+    if instances_lock.template_in_use(&name).await {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    instances_lock.templates().delete(&name).await.map_err(to_status)?;
+
+    info!("Template {} deleted", name);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn to_status(e: Error) -> StatusCode {
+    match e {
+        Error::NotFound(_) | Error::VersionNotFound(_, _) => StatusCode::NOT_FOUND,
+        Error::InvalidName(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        e => {
+            error!("Template library error: {}", e);
+
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use axum::{extract::{Query, State}, Json};
+use serde::Deserialize;
+
+use crate::{AppState, webhook::Delivery};
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+    /// Only return deliveries to the target with this name
+    pub target: Option<String>,
+    /// Only return this many of the most recent deliveries
+    pub limit: Option<usize>,
+}
+
+pub async fn get_deliveries(
+    State(state): State<AppState>,
+    Query(query): Query<DeliveryQuery>,
+) -> Json<Vec<Delivery>> {
+    Json(state.webhooks.deliveries(query.target.as_deref(), query.limit).await)
+}
@@ -0,0 +1,122 @@
+use axum_server::tls_rustls::RustlsConfig;
+use std::{path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex, task::JoinHandle};
+use tracing::{debug, error, info};
+
+use crate::config::{ConfigFile, TlsConfig};
+
+/// Interval between checks of the certificate and key for modifications
+const WATCH_INTERVAL_MS: u64 = 5000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("Certificate generation error: {0}")]
+    CertGen(rcgen::Error),
+    #[error("No TLS certificate path set in config")]
+    NoCertPath,
+    #[error("No TLS key path set in config")]
+    NoKeyPath,
+}
+
+/// Loads the certificate and key, generating a self-signed pair first if
+/// enabled and neither file exists yet
+pub async fn load(tls: &TlsConfig, address: &str) -> Result<RustlsConfig, Error> {
+    let (cert_path, key_path) = paths(tls)?;
+
+    if tls.self_signed && !cert_path.exists() && !key_path.exists() {
+        generate_self_signed(&cert_path, &key_path, address).await?;
+    }
+
+    RustlsConfig::from_pem_file(&cert_path, &key_path).await.map_err(Error::Io)
+}
+
+/// Paths of the certificate and key, along with their modification times
+type WatchState = Option<(PathBuf, PathBuf, Option<SystemTime>, Option<SystemTime>)>;
+
+/// Reloads the certificate and key whenever either file is modified, or
+/// when their paths are changed in the config
+pub async fn watch(rustls_config: RustlsConfig, config: Arc<Mutex<ConfigFile>>) -> JoinHandle<()> {
+    let mut last_state = watch_state(&config).await;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_millis(WATCH_INTERVAL_MS)).await;
+
+            let state = watch_state(&config).await;
+
+            if state == last_state {
+                continue;
+            }
+
+            if let Some((cert_path, key_path, _, _)) = &state {
+                debug!("TLS certificate or key changed, reloading");
+
+                match rustls_config.reload_from_pem_file(cert_path, key_path).await {
+                    Ok(_) => info!("TLS certificate reloaded"),
+                    Err(e) => error!("Unable to reload TLS certificate, keeping current certificate: {}", e),
+                };
+            }
+
+            last_state = state;
+        }
+    })
+}
+
+async fn generate_self_signed(cert_path: &Path, key_path: &Path, address: &str) -> Result<(), Error> {
+    info!("Generating self-signed TLS certificate");
+
+    let mut names = vec!["localhost".to_string()];
+
+    if !address.parse::<std::net::IpAddr>().is_ok_and(|a| a.is_unspecified()) {
+        names.push(address.to_string());
+    }
+
+    let cert = rcgen::generate_simple_self_signed(names).map_err(Error::CertGen)?;
+
+    for path in [cert_path, key_path] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.map_err(Error::Io)?;
+        }
+    }
+
+    fs::write(cert_path, cert.cert.pem()).await.map_err(Error::Io)?;
+    write_private(key_path, cert.key_pair.serialize_pem().as_bytes()).await?;
+
+    info!("Self-signed TLS certificate written to {}", cert_path.display());
+
+    Ok(())
+}
+
+/// Creates the file readable only by its owner
+async fn write_private(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut f = options.open(path).await.map_err(Error::Io)?;
+
+    f.write_all(contents).await.map_err(Error::Io)?;
+    f.flush().await.map_err(Error::Io)?;
+
+    Ok(())
+}
+
+fn paths(tls: &TlsConfig) -> Result<(PathBuf, PathBuf), Error> {
+    Ok((
+        tls.cert_path.clone().ok_or(Error::NoCertPath)?,
+        tls.key_path.clone().ok_or(Error::NoKeyPath)?,
+    ))
+}
+
+async fn watch_state(config: &Arc<Mutex<ConfigFile>>) -> WatchState {
+    let (cert_path, key_path) = paths(&config.lock().await.config.tls).ok()?;
+
+    let cert_modified = fs::metadata(&cert_path).await.and_then(|m| m.modified()).ok();
+    let key_modified = fs::metadata(&key_path).await.and_then(|m| m.modified()).ok();
+
+    Some((cert_path, key_path, cert_modified, key_modified))
+}
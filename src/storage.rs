@@ -7,7 +7,6 @@ use tokio::{
     fs,
     io::AsyncWriteExt,
 };
-use tracing::error;
 
 use crate::{config::Config, instance::{StoredInstanceList, StoredInstance}};
 
@@ -69,9 +68,6 @@ impl JsonStorageProvider {
 
         Ok(())
     }
-    pub async fn get_instance<I: std::fmt::Display>(&self, id: I) -> Option<StoredInstance> {
-        self.data.instances.get(&id.to_string()).cloned()
-    }
     /// `true` is returned if the instance was removed from storage
     pub async fn del_instance<I: std::fmt::Display>(&mut self, id: I) -> Result<bool, Error> {
         let deleted = self.data.instances.remove(&id.to_string()).is_some();
@@ -87,12 +83,24 @@ impl JsonStorageProvider {
 
         Ok(())
     }
+    /// Writes any pending changes to disk
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.update().await
+    }
+    /// Writes to a temporary file first, so an interrupted write can't
+    /// leave the store truncated
     async fn update(&self) -> Result<(), Error> {
-        let mut f = fs::File::create(&self.path).await.map_err(Error::Io)?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut f = fs::File::create(&tmp_path).await.map_err(Error::Io)?;
 
         let config_raw = serde_jsonc::to_string_pretty(&self.data).map_err(Error::JsonEncode)?;
 
         f.write_all(config_raw.as_bytes()).await.map_err(Error::Io)?;
+        f.sync_all().await.map_err(Error::Io)?;
+
+        fs::rename(&tmp_path, &self.path).await.map_err(Error::Io)?;
 
         Ok(())
     }
@@ -0,0 +1,258 @@
+//! Structural checks of Volkanic templates, run before an instance is
+//! created so problems surface in the API rather than inside the host
+//!
+//! Only the parts of the format the runner itself reads are checked: the
+//! template must decode to a JSON object, and `variables` is an optional
+//! array of `{ name, type, default?, required? }` with `type` one of
+//! [`VARIABLE_TYPES`].
+//!
+//! Every other field is left to the host.
+
+use serde::{Deserialize, Serialize};
+use serde_jsonc::Value;
+use std::{collections::{HashMap, HashSet}, fmt};
+
+pub const VARIABLE_TYPES: &[&str] = &["string", "int", "float", "bool"];
+
+/// A problem found in a template
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TemplateIssue {
+    /// Dot-separated path of the offending field, empty for the template
+    /// as a whole
+    pub field: String,
+    pub message: String,
+}
+
+impl TemplateIssue {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for TemplateIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.field.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "`{}`: {}", self.field, self.message),
+        }
+    }
+}
+
+/// Decodes the template, returning every problem found
+pub fn check(data: &[u8]) -> Vec<TemplateIssue> {
+    let template = match parse(data) {
+        Ok(o) => o,
+        Err(issue) => return vec![issue],
+    };
+
+    let mut issues = Vec::new();
+
+    let Some(template) = template.as_object() else {
+        return vec![TemplateIssue::new("", "must be a JSON object")];
+    };
+
+    match template.get("variables") {
+        Some(Value::Array(variables)) => check_variables(variables, &mut issues),
+        Some(_) => issues.push(TemplateIssue::new("variables", "must be an array")),
+        None => {}
+    };
+
+    issues
+}
+
+/// A variable declared by a template, which instances can set a value for
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct VariableDecl {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub var_type: String,
+    #[serde(default)]
+    pub default: Option<Value>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Returns the variables declared by an already checked template
+pub fn variables(data: &[u8]) -> Vec<VariableDecl> {
+    parse(data).ok()
+        .and_then(|t| t.get("variables").cloned())
+        .and_then(|v| serde_jsonc::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Checks an instance's values against the template's declarations
+pub fn check_values(decls: &[VariableDecl], values: &HashMap<String, Value>) -> Vec<TemplateIssue> {
+    let mut issues = Vec::new();
+
+    let mut names: Vec<&String> = values.keys().collect();
+    names.sort();
+
+    for name in names {
+        let field = format!("variables.{}", name);
+
+        match decls.iter().find(|d| &d.name == name) {
+            Some(decl) => {
+                if !matches_type(&decl.var_type, &values[name]) {
+                    issues.push(TemplateIssue::new(field, format!("must be of type {}", decl.var_type)));
+                }
+            }
+            None => issues.push(TemplateIssue::new(field, "is not declared by the template")),
+        };
+    }
+
+    for decl in decls {
+        if decl.required && decl.default.is_none() && !values.contains_key(&decl.name) {
+            issues.push(TemplateIssue::new(format!("variables.{}", decl.name), "is required by the template"));
+        }
+    }
+
+    issues
+}
+
+/// Merges the instance's values over the declared defaults. Variables
+/// without a value or default are left out.
+pub fn resolve_values(decls: &[VariableDecl], values: &HashMap<String, Value>) -> HashMap<String, Value> {
+    decls.iter()
+        .filter_map(|d| {
+            values.get(&d.name)
+                .or(d.default.as_ref())
+                .map(|v| (d.name.clone(), v.clone()))
+        })
+        .collect()
+}
+
+/// Decodes the template as JSON, allowing comments
+pub fn parse(data: &[u8]) -> Result<Value, TemplateIssue> {
+    let text = std::str::from_utf8(data)
+        .map_err(|_| TemplateIssue::new("", "is not valid UTF-8 text"))?;
+
+    serde_jsonc::from_str(text)
+        .map_err(|e| TemplateIssue::new("", format!("is not valid JSON: {}", e)))
+}
+
+fn check_variables(variables: &[Value], issues: &mut Vec<TemplateIssue>) {
+    let mut names = HashSet::new();
+
+    for (i, variable) in variables.iter().enumerate() {
+        let field = format!("variables.{}", i);
+
+        let Some(variable) = variable.as_object() else {
+            issues.push(TemplateIssue::new(field, "must be an object"));
+            continue;
+        };
+
+        match variable.get("name") {
+            Some(Value::String(name)) if !name.is_empty() => {
+                if !names.insert(name.clone()) {
+                    issues.push(TemplateIssue::new(format!("{}.name", field), format!("\"{}\" is declared more than once", name)));
+                }
+            }
+            _ => issues.push(TemplateIssue::new(format!("{}.name", field), "must be a non-empty string")),
+        };
+
+        let var_type = match variable.get("type") {
+            Some(Value::String(t)) if VARIABLE_TYPES.contains(&t.as_str()) => Some(t.as_str()),
+            Some(Value::String(t)) => {
+                issues.push(TemplateIssue::new(
+                    format!("{}.type", field),
+                    format!("unknown type \"{}\" (expected one of: {})", t, VARIABLE_TYPES.join(", ")),
+                ));
+                None
+            }
+            _ => {
+                issues.push(TemplateIssue::new(format!("{}.type", field), "is required"));
+                None
+            }
+        };
+
+        if let (Some(var_type), Some(default)) = (var_type, variable.get("default")) {
+            if !matches_type(var_type, default) {
+                issues.push(TemplateIssue::new(format!("{}.default", field), format!("must be of type {}", var_type)));
+            }
+        }
+
+        if variable.get("required").is_some_and(|r| !r.is_boolean()) {
+            issues.push(TemplateIssue::new(format!("{}.required", field), "must be a boolean"));
+        }
+    }
+}
+
+/// Whether the value is valid for a variable of the given type
+pub fn matches_type(var_type: &str, value: &Value) -> bool {
+    match var_type {
+        "string" => value.is_string(),
+        "int" => value.is_i64() || value.is_u64(),
+        "float" => value.is_number(),
+        "bool" => value.is_boolean(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let valid = br#"{
+            // Comments are allowed
+            "name": "Lobby",
+            "server": { "type": "paper" },
+            "variables": [{ "name": "motd", "type": "string", "default": "Hi" }]
+        }"#;
+
+        assert_eq!(check(valid), vec![]);
+
+        let invalid = br#"{
+            "variables": [
+                { "name": "a", "type": "int", "default": "1" },
+                { "name": "a", "type": "colour" },
+                { "type": "bool", "required": "yes" }
+            ]
+        }"#;
+
+        let fields: Vec<String> = check(invalid).into_iter().map(|i| i.field).collect();
+
+        assert_eq!(fields, vec![
+            "variables.0.default",
+            "variables.1.name",
+            "variables.1.type",
+            "variables.2.name",
+            "variables.2.required",
+        ]);
+
+        assert_eq!(check(b"not json").len(), 1);
+        assert_eq!(check(b"[]").len(), 1);
+        assert_eq!(check(br#"{ "variables": {} }"#).len(), 1);
+    }
+    #[test]
+    fn test_values() {
+        let decls = variables(br#"{
+            "variables": [
+                { "name": "motd", "type": "string", "default": "Hi" },
+                { "name": "max_players", "type": "int", "required": true },
+                { "name": "hardcore", "type": "bool" }
+            ]
+        }"#);
+
+        let values = HashMap::from([
+            ("motd".to_string(), Value::from(5)),
+            ("seed".to_string(), Value::from("x")),
+        ]);
+
+        let fields: Vec<String> = check_values(&decls, &values).into_iter().map(|i| i.field).collect();
+
+        assert_eq!(fields, vec!["variables.motd", "variables.seed", "variables.max_players"]);
+
+        let values = HashMap::from([("max_players".to_string(), Value::from(20))]);
+
+        assert!(check_values(&decls, &values).is_empty());
+        assert_eq!(resolve_values(&decls, &values), HashMap::from([
+            ("motd".to_string(), Value::from("Hi")),
+            ("max_players".to_string(), Value::from(20)),
+        ]));
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::{fs, sync::Mutex};
+
+use crate::config::Config;
+
+mod check;
+
+pub use check::{check, check_values, resolve_values, variables, TemplateIssue};
+
+/// Directory next to the storage file holding the template library
+const TEMPLATES_DIR_NAME: &str = "templates";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("No JSON storage path set in config")]
+    NoStoragePath,
+    #[error("Invalid template name: {0}")]
+    InvalidName(String),
+    #[error("Template not found: {0}")]
+    NotFound(String),
+    #[error("Template version not found: {0} version {1}")]
+    VersionNotFound(String, u32),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    /// Every stored version, oldest first
+    pub versions: Vec<u32>,
+    pub latest: u32,
+}
+
+/// Templates stored by the runner, each kept as a series of immutable
+/// versions at `templates/<name>/<version>`
+#[derive(Debug)]
+pub struct TemplateStore {
+    dir: PathBuf,
+    /// Held while adding or removing versions
+    write_lock: Mutex<()>,
+}
+
+impl TemplateStore {
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let storage_path = config.storage.path.as_ref().ok_or(Error::NoStoragePath)?;
+
+        let dir = match storage_path.parent() {
+            Some(p) => p.join(TEMPLATES_DIR_NAME),
+            None => PathBuf::from(TEMPLATES_DIR_NAME),
+        };
+
+        Ok(Self {
+            dir,
+            write_lock: Mutex::new(()),
+        })
+    }
+    pub async fn list(&self) -> Result<Vec<TemplateInfo>, Error> {
+        let mut templates = Vec::new();
+
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(templates),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            if validate_name(&name).is_err() {
+                continue;
+            }
+            if let Ok(info) = self.info(&name).await {
+                templates.push(info);
+            }
+        }
+
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(templates)
+    }
+    pub async fn info(&self, name: &str) -> Result<TemplateInfo, Error> {
+        let versions = self.versions(name).await?;
+
+        let latest = *versions.last().ok_or(Error::NotFound(name.to_string()))?;
+
+        Ok(TemplateInfo {
+            name: name.to_string(),
+            versions,
+            latest,
+        })
+    }
+    /// Returns the requested version, or the latest if `None`, along with
+    /// its version number
+    pub async fn get(&self, name: &str, version: Option<u32>) -> Result<(u32, Vec<u8>), Error> {
+        let version = match version {
+            Some(o) => o,
+            None => self.info(name).await?.latest,
+        };
+
+        match fs::read(self.version_path(name, version)?).await {
+            Ok(o) => Ok((version, o)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::VersionNotFound(name.to_string(), version)),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+    /// Stores the data as a new version, creating the template if needed
+    pub async fn add_version(&self, name: &str, data: &[u8]) -> Result<u32, Error> {
+        validate_name(name)?;
+
+        let _lock = self.write_lock.lock().await;
+
+        let version = match self.versions(name).await {
+            Ok(versions) => versions.last().map(|v| v + 1).unwrap_or(1),
+            Err(Error::NotFound(_)) => 1,
+            Err(e) => return Err(e),
+        };
+
+        fs::create_dir_all(self.dir.join(name)).await.map_err(Error::Io)?;
+        fs::write(self.version_path(name, version)?, data).await.map_err(Error::Io)?;
+
+        Ok(version)
+    }
+    pub async fn delete(&self, name: &str) -> Result<(), Error> {
+        validate_name(name)?;
+
+        let _lock = self.write_lock.lock().await;
+
+        match fs::remove_dir_all(self.dir.join(name)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::NotFound(name.to_string())),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+    async fn versions(&self, name: &str) -> Result<Vec<u32>, Error> {
+        validate_name(name)?;
+
+        let mut entries = match fs::read_dir(self.dir.join(name)).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Error::NotFound(name.to_string())),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut versions = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            if let Ok(version) = entry.file_name().to_string_lossy().parse::<u32>() {
+                versions.push(version);
+            }
+        }
+
+        versions.sort();
+
+        Ok(versions)
+    }
+    fn version_path(&self, name: &str, version: u32) -> Result<PathBuf, Error> {
+        validate_name(name)?;
+
+        Ok(self.dir.join(name).join(version.to_string()))
+    }
+}
+
+/// Names are used as directory names, so are limited to a safe set of
+/// characters
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidName(name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("paper-1.21_lobby").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("..").is_err());
+        assert!(validate_name("a/b").is_err());
+    }
+}
@@ -0,0 +1,431 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::Discriminant,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::{broadcast::error::RecvError, Mutex}, task::JoinHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{ConfigFile, WebhookFormat, WebhookTarget, WebhooksConfig},
+    global_event::{EventBus, EventFilter, GlobalEvent, StampedEvent},
+    instance::{InstanceStatus, StopReason},
+};
+
+/// Caps the doubling delay between attempts
+const MAX_BACKOFF_SECS: u64 = 300;
+const SIGNATURE_HEADER: &str = "X-Runner-Signature";
+const EVENT_HEADER: &str = "X-Runner-Event";
+const DELIVERY_HEADER: &str = "X-Runner-Delivery";
+/// Unix time the request was signed at, so targets can reject replays
+const TIMESTAMP_HEADER: &str = "X-Runner-Timestamp";
+
+type DeliveryLog = Arc<Mutex<VecDeque<Delivery>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DeliveryStatus {
+    #[serde(rename = "pending")]
+    Pending,
+    #[serde(rename = "delivered")]
+    Delivered,
+    #[serde(rename = "failed")]
+    Failed,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Attempt {
+    pub timestamp: DateTime<Utc>,
+    /// Not set if no response was received
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// An event sent to a single webhook target
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub target: String,
+    pub event_id: u64,
+    pub kind: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<Attempt>,
+}
+
+/// Body posted to targets using the `json` format
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    delivery_id: &'a str,
+    event_id: u64,
+    kind: &'a str,
+    timestamp: DateTime<Utc>,
+    event: &'a GlobalEvent,
+}
+
+/// Posts global events to the webhook targets in the config, which is
+/// re-read for every event so reloads take effect immediately
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    config: Arc<Mutex<ConfigFile>>,
+    client: reqwest::Client,
+    log: DeliveryLog,
+    /// Last status seen for each instance, so chat-style targets are only
+    /// posted to when it changes
+    statuses: Arc<Mutex<HashMap<String, Discriminant<InstanceStatus>>>>,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl WebhookDispatcher {
+    pub fn new(config: Arc<Mutex<ConfigFile>>, shutdown: CancellationToken, tasks: TaskTracker) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            log: Arc::new(Mutex::new(VecDeque::new())),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            shutdown,
+            tasks,
+        }
+    }
+    pub fn start(&self, events: &EventBus) -> JoinHandle<()> {
+        let dispatcher = self.clone();
+        let mut rx = events.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => dispatcher.dispatch(event).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Webhook dispatcher lagged behind, {} events weren't delivered", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+    /// Most recent deliveries first
+    pub async fn deliveries(&self, target: Option<&str>, limit: Option<usize>) -> Vec<Delivery> {
+        self.log.lock().await.iter()
+            .rev()
+            .filter(|d| target.is_none_or(|t| d.target == t))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+    async fn dispatch(&self, event: StampedEvent) {
+        let settings = self.config.lock().await.config.webhooks.clone();
+
+        let status_changed = self.track_status(&event.event).await;
+
+        for target in &settings.targets {
+            let filter = EventFilter {
+                instances: target.instances.clone(),
+                kinds: target.kinds.clone(),
+            };
+
+            if !filter.matches(&event.event) {
+                continue;
+            }
+            // Host logs are too frequent to send unless asked for by kind
+            if target.kinds.is_none() && matches!(event.event, GlobalEvent::HostLog { .. }) {
+                continue;
+            }
+            // Chat messages are only worth posting when the status changes,
+            // not for progress or other modifications
+            if target.format != WebhookFormat::Json && matches!(event.event, GlobalEvent::ModifyInstance { .. }) && !status_changed {
+                continue;
+            }
+
+            let delivery = Delivery {
+                id: Uuid::new_v4().to_string(),
+                target: target.name.clone(),
+                event_id: event.id,
+                kind: event.event.kind().to_string(),
+                status: DeliveryStatus::Pending,
+                attempts: Vec::new(),
+            };
+
+            let body = match encode(target.format, &delivery.id, &event) {
+                Ok(o) => o,
+                Err(e) => {
+                    error!("Error encoding webhook payload: {}", e);
+
+                    continue;
+                }
+            };
+
+            self.tasks.spawn(deliver(
+                self.client.clone(),
+                self.log.clone(),
+                self.shutdown.clone(),
+                target.clone(),
+                settings.clone(),
+                delivery,
+                body,
+            ));
+        }
+    }
+    /// Returns whether the event changes the instance's status, ignoring
+    /// progress while creating
+    async fn track_status(&self, event: &GlobalEvent) -> bool {
+        let mut statuses = self.statuses.lock().await;
+
+        match event {
+            GlobalEvent::ModifyInstance { id, instance } => {
+                let status = std::mem::discriminant(&instance.status);
+
+                statuses.insert(id.clone(), status) != Some(status)
+            }
+            GlobalEvent::DeleteInstance { id } => {
+                statuses.remove(id);
+
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Signature of `body` signed at `timestamp`, as sent in the signature
+/// header
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+
+    let digest: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("sha256={}", digest)
+}
+
+fn encode(format: WebhookFormat, delivery_id: &str, event: &StampedEvent) -> Result<Vec<u8>, serde_jsonc::Error> {
+    match format {
+        WebhookFormat::Json => serde_jsonc::to_vec(&Payload {
+            delivery_id,
+            event_id: event.id,
+            kind: event.event.kind(),
+            timestamp: Utc::now(),
+            event: &event.event,
+        }),
+        WebhookFormat::Discord => serde_jsonc::to_vec(&serde_jsonc::json!({ "content": summary(&event.event) })),
+        WebhookFormat::Slack => serde_jsonc::to_vec(&serde_jsonc::json!({ "text": summary(&event.event) })),
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    log: DeliveryLog,
+    shutdown: CancellationToken,
+    target: WebhookTarget,
+    settings: WebhooksConfig,
+    delivery: Delivery,
+    body: Vec<u8>,
+) {
+    let id = delivery.id.clone();
+    let kind = delivery.kind.clone();
+
+    record(&log, delivery, settings.log_size).await;
+
+    for attempt in 1..=settings.max_attempts {
+        // Signed again for each attempt, so retries carry a fresh timestamp
+        let timestamp = Utc::now().timestamp();
+
+        let mut request = client.post(&target.url)
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &kind)
+            .header(DELIVERY_HEADER, &id)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .body(body.clone());
+
+        if let Some(secret) = &target.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, timestamp, &body));
+        }
+
+        let (result, retry) = match request.send().await {
+            Ok(res) => {
+                let status = res.status();
+
+                let result = Attempt {
+                    timestamp: Utc::now(),
+                    status_code: Some(status.as_u16()),
+                    error: (!status.is_success()).then(|| format!("Target responded with {}", status)),
+                };
+
+                // Other client errors won't be fixed by retrying
+                let retry = status.is_server_error()
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || status == reqwest::StatusCode::REQUEST_TIMEOUT;
+
+                (result, retry)
+            }
+            Err(e) => (Attempt { timestamp: Utc::now(), status_code: None, error: Some(e.to_string()) }, true),
+        };
+
+        let delivered = result.error.is_none();
+
+        update(&log, &id, |d| {
+            d.attempts.push(result);
+
+            if delivered {
+                d.status = DeliveryStatus::Delivered;
+            }
+        }).await;
+
+        if delivered {
+            debug!("Delivered webhook {} to {}", id, target.name);
+
+            return;
+        }
+        if !retry || attempt == settings.max_attempts {
+            break;
+        }
+
+        let backoff = settings.backoff_secs.saturating_mul(2u64.saturating_pow(attempt - 1)).min(MAX_BACKOFF_SECS);
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(backoff)) => {}
+            _ = shutdown.cancelled() => break,
+        }
+    }
+
+    warn!("Failed to deliver webhook {} to {}", id, target.name);
+
+    update(&log, &id, |d| d.status = DeliveryStatus::Failed).await;
+}
+
+async fn record(log: &DeliveryLog, delivery: Delivery, log_size: usize) {
+    let mut log = log.lock().await;
+
+    log.push_back(delivery);
+
+    while log.len() > log_size {
+        log.pop_front();
+    }
+}
+
+/// Deliveries pushed out of the log are no longer updated
+async fn update<T>(log: &DeliveryLog, id: &str, f: impl FnOnce(&mut Delivery) -> T) -> Option<T> {
+    log.lock().await.iter_mut()
+        .find(|d| d.id == id)
+        .map(f)
+}
+
+/// Human-readable description of the event, for chat-style targets
+fn summary(event: &GlobalEvent) -> String {
+    match event {
+        GlobalEvent::ModifyInstance { instance, .. } => match (&instance.status, &instance.last_stop_reason) {
+            (InstanceStatus::Inactive, Some(StopReason::Crashed)) => match instance.last_exit_code {
+                Some(code) => format!("Instance {} crashed with exit code {}", instance.name, code),
+                None => format!("Instance {} crashed", instance.name),
+            },
+            (status, _) => format!("Instance {} is {}", instance.name, status_name(status)),
+        },
+        GlobalEvent::DeleteInstance { id } => format!("Instance {} was deleted", id),
+        GlobalEvent::HostUnresponsive { id, missed, .. } => {
+            format!("Instance {} is unresponsive after {} missed heartbeats", id, missed)
+        }
+        GlobalEvent::HostLog { id, records } => format!("Instance {} logged {} records", id, records.len()),
+        GlobalEvent::ResyncRequired {} => "Events were missed".to_string(),
+    }
+}
+
+fn status_name(status: &InstanceStatus) -> String {
+    match status {
+        InstanceStatus::Inactive => "inactive".to_string(),
+        InstanceStatus::Running => "running".to_string(),
+        InstanceStatus::Creating(percentage) => format!("creating ({}%)", percentage),
+        InstanceStatus::Deleting => "deleting".to_string(),
+        InstanceStatus::Starting => "starting".to_string(),
+        InstanceStatus::Stopping => "stopping".to_string(),
+        InstanceStatus::Unresponsive => "unresponsive".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::{HeaderMap, StatusCode}, routing::post, Router};
+
+    #[tokio::test]
+    async fn test_deliver() {
+        // Stand-in target which fails the first attempt, recording the
+        // signature, timestamp and body of each request
+        let received = Arc::new(Mutex::new(Vec::<(Option<String>, i64, Vec<u8>)>::new()));
+
+        let app = Router::new().route("/hook", post({
+            let received = received.clone();
+
+            move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                let mut received = received.lock().await;
+
+                let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(String::from);
+
+                let timestamp = header(TIMESTAMP_HEADER).and_then(|t| t.parse().ok()).unwrap_or_default();
+                received.push((header(SIGNATURE_HEADER), timestamp, body.to_vec()));
+
+                match received.len() {
+                    1 => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::OK,
+                }
+            }
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let target = WebhookTarget {
+            name: "test".to_string(),
+            url: format!("http://{}/hook", address),
+            secret: Some("secret".to_string()),
+            format: WebhookFormat::Json,
+            instances: None,
+            kinds: None,
+        };
+
+        let settings = WebhooksConfig {
+            targets: vec![target.clone()],
+            backoff_secs: 0,
+            ..Default::default()
+        };
+
+        let event = StampedEvent { id: 1, event: GlobalEvent::DeleteInstance { id: "a".to_string() } };
+
+        let delivery = Delivery {
+            id: "delivery".to_string(),
+            target: target.name.clone(),
+            event_id: event.id,
+            kind: event.event.kind().to_string(),
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+        };
+
+        let body = encode(target.format, &delivery.id, &event).unwrap();
+        let log: DeliveryLog = Arc::new(Mutex::new(VecDeque::new()));
+
+        deliver(reqwest::Client::new(), log.clone(), CancellationToken::new(), target, settings, delivery, body).await;
+
+        let delivery = log.lock().await[0].clone();
+
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts.iter().map(|a| a.status_code).collect::<Vec<_>>(), vec![Some(500), Some(200)]);
+
+        let received = received.lock().await;
+        let (signature, timestamp, body) = &received[1];
+
+        assert_eq!(signature.as_deref(), Some(sign("secret", *timestamp, body).as_str()));
+        assert_ne!(sign("secret", timestamp + 1, body), sign("secret", *timestamp, body));
+    }
+}